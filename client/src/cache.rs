@@ -10,6 +10,8 @@ impl Cache {
         let cache_dir = PathBuf::from(path).join(".qfs");
         fs::create_dir_all(cache_dir.join("data"))
             .expect("Failure creating the ~/.qfs directory");
+        fs::create_dir_all(cache_dir.join("transactions"))
+            .expect("Failure creating the ~/.qfs/transactions directory");
         Self {
             cache_dir,
         }
@@ -19,6 +21,14 @@ impl Cache {
         self.cache_dir.join("data")
     }
 
+    pub fn bookkeeping_file(&self) -> PathBuf {
+        self.cache_dir.join("cache.db")
+    }
+
+    pub fn transactions_dir(&self) -> PathBuf {
+        self.cache_dir.join("transactions")
+    }
+
     pub fn main_dir(&self) -> PathBuf {
         self.cache_dir.clone()
     }
@@ -0,0 +1,10 @@
+use quantumfs::models::transaction::Transaction;
+
+use crate::commands::staging_dir;
+
+pub fn abort() {
+    let transaction = Transaction::load(staging_dir().as_path())
+        .expect("Failure loading the transaction");
+    transaction.abort()
+        .expect("Failure aborting the transaction");
+}
@@ -0,0 +1,42 @@
+use web3::types::Address;
+
+use quantumfs::operations::ethereum;
+
+use crate::commands::{client_address, load_contract, owner_address, repository_name};
+
+fn parse_publisher(publisher: &str) -> Address {
+    ethereum::parse_address(publisher)
+        .expect("Invalid publisher address")
+}
+
+/// Only the owner manages the publishers of a file system, so changes are
+/// refused when `--owner` names another account than the user's.
+fn owner_account() -> Address {
+    let (address, owner) = (client_address(), owner_address());
+    if address != owner {
+        panic!("Only {:x} can change the publishers of its file system, not {:x}", owner, address);
+    }
+    address
+}
+
+pub fn grant(publisher: &str) {
+    let publisher = parse_publisher(publisher);
+    ethereum::grant_publisher(&load_contract(), owner_account(), &repository_name(), publisher)
+        .expect("Failure granting the publisher");
+    println!("Granted {:x} to publish to {}", publisher, repository_name());
+}
+
+pub fn revoke(publisher: &str) {
+    let publisher = parse_publisher(publisher);
+    ethereum::revoke_publisher(&load_contract(), owner_account(), &repository_name(), publisher)
+        .expect("Failure revoking the publisher");
+    println!("Revoked {:x} from publishing to {}", publisher, repository_name());
+}
+
+pub fn list() {
+    let publishers = ethereum::fetch_publishers(&load_contract(), owner_address(), &repository_name())
+        .expect("Failure fetching the publishers");
+    for publisher in publishers {
+        println!("{:x}", publisher);
+    }
+}
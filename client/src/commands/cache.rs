@@ -0,0 +1,16 @@
+use crate::commands::load_cache;
+
+pub fn stats() {
+    let stats = load_cache().stats()
+        .expect("Failure reading the cache statistics");
+    println!("Objects: {}", stats.objects);
+    println!("Size: {} bytes", stats.size);
+    println!("Quota: {} bytes", stats.quota);
+    println!("Pinned objects: {}", stats.pinned);
+}
+
+pub fn clean() {
+    let (objects, size) = load_cache().clean()
+        .expect("Failure cleaning the cache");
+    println!("Evicted {} objects ({} bytes)", objects, size);
+}
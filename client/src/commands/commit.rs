@@ -1,3 +1,17 @@
-pub fn commit() {
+use quantumfs::models::transaction::Transaction;
+
+use crate::commands::{load_repository, staging_dir};
+use crate::settings::SETTINGS;
 
+pub fn commit() {
+    let repository = load_repository();
+    let max_entries = SETTINGS.get::<i64>("catalog_max_entries")
+        .expect("Invalid maximum number of entries per catalog");
+    let chunk_size = SETTINGS.get::<u64>("chunk_size")
+        .expect("Invalid chunk size");
+    let transaction = Transaction::load(staging_dir().as_path())
+        .expect("Failure loading the transaction");
+    let tag = transaction.commit(repository.store(), max_entries, chunk_size)
+        .expect("Failure committing the transaction");
+    println!("Committed root catalog {} as revision {}", tag.hash(), tag.revision());
 }
@@ -1,4 +1,141 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use web3::contract::Contract;
+use web3::transports::Http;
+use web3::types::Address;
+
+use quantumfs::models::cache::CacheManager;
+use quantumfs::models::ledger::{LocalLedger, RevisionLedger};
+use quantumfs::models::manifest::Manifest;
+use quantumfs::models::repository::Repository;
+use quantumfs::operations::ethereum;
+use quantumfs::operations::ipfs::IPFS;
+use quantumfs::operations::store::{DirectoryStore, ObjectStore};
+
+use crate::cache::CACHE;
+use crate::ownership::{OwnerMap, Ownership};
+use crate::settings::SETTINGS;
+
+pub mod abort;
+pub mod acl;
+pub mod cache;
 pub mod commit;
 pub mod mount;
 pub mod push;
 pub mod transaction;
+
+fn client_address() -> Address {
+    SETTINGS.get::<Address>("address")
+        .expect("User ethereum address not provided")
+}
+
+/// Account whose file system is mounted, the client's own unless another is given.
+fn owner_address() -> Address {
+    match SETTINGS.get::<Address>("owner") {
+        Ok(owner) => owner,
+        Err(_) => client_address(),
+    }
+}
+
+fn contract_address() -> Address {
+    SETTINGS.get::<Address>("contract")
+        .expect("Contract ethereum address not provided")
+}
+
+fn repository_name() -> String {
+    SETTINGS.get::<String>("repository")
+        .expect("Repository name not provided")
+}
+
+fn web3_url() -> String {
+    SETTINGS.get::<String>("web3")
+        .expect("Web3 URL not provided")
+}
+
+fn load_contract() -> Contract<Http> {
+    let web3 = ethereum::get_web3(&web3_url());
+    ethereum::get_contract(&web3, contract_address())
+}
+
+fn load_store() -> Arc<dyn ObjectStore> {
+    let storage = SETTINGS.get::<String>("storage")
+        .expect("Storage backend not provided");
+    match storage.as_str() {
+        "ipfs" => {
+            let ipfs_server = SETTINGS.get::<String>("ipfs-server")
+                .expect("IPFS server not provided");
+            let ipfs_port = SETTINGS.get::<u16>("ipfs-port")
+                .expect("IPFS port not provided");
+            Arc::new(IPFS::new(&ipfs_server, ipfs_port))
+        }
+        "directory" => {
+            let storage_dir = SETTINGS.get::<String>("storage_dir")
+                .expect("Storage directory not provided");
+            let store = DirectoryStore::new(Path::new(&storage_dir))
+                .expect("Failure opening the storage directory");
+            Arc::new(store)
+        }
+        _ => panic!("Unknown storage backend {}", storage),
+    }
+}
+
+fn load_ledger() -> Box<dyn RevisionLedger> {
+    let ledger = SETTINGS.get::<String>("ledger")
+        .expect("Ledger backend not provided");
+    match ledger.as_str() {
+        "ethereum" => {
+            let web3_url = web3_url();
+            let manifest = Manifest::new(owner_address(), &repository_name(), contract_address(), &web3_url);
+            match SETTINGS.get::<Address>("address") {
+                Ok(address) => Box::new(manifest.with_account(address)),
+                Err(_) => Box::new(manifest),
+            }
+        }
+        "local" => {
+            let ledger_file = SETTINGS.get::<String>("ledger_file")
+                .expect("Ledger file not provided");
+            // the local ledger keeps each named file system as a separate owner
+            let owner = format!("{:x}/{}", owner_address(), repository_name());
+            let ledger = LocalLedger::open(Path::new(&ledger_file), &owner)
+                .expect("Failure opening the ledger file");
+            Box::new(ledger)
+        }
+        _ => panic!("Unknown ledger backend {}", ledger),
+    }
+}
+
+pub fn load_ownership() -> Ownership {
+    let ownership = SETTINGS.get::<String>("ownership")
+        .expect("Ownership mode not provided");
+    match ownership.as_str() {
+        "preserve" => Ownership::Preserve,
+        "user" => Ownership::MountingUser,
+        "map" => {
+            let map_file = SETTINGS.get::<String>("ownership_map")
+                .expect("Ownership map file not provided");
+            let map = OwnerMap::load(Path::new(&map_file))
+                .expect("Failure loading the ownership map");
+            Ownership::Map(map)
+        }
+        _ => panic!("Unknown ownership mode {}", ownership),
+    }
+}
+
+pub fn load_cache() -> Arc<CacheManager> {
+    let quota = SETTINGS.get::<u64>("cache_quota")
+        .expect("Invalid cache quota");
+    let cache = CacheManager::open(CACHE.data_dir().as_path(), CACHE.bookkeeping_file().as_path(), quota)
+        .expect("Failure opening the cache");
+    Arc::new(cache)
+}
+
+pub fn load_repository() -> Repository {
+    Repository::new(&repository_name(), load_ledger(), load_store(), CACHE.data_dir().as_path())
+        .with_cache(load_cache())
+}
+
+pub fn staging_dir() -> PathBuf {
+    let repository_id = format!("{:x}-{:x}-{}", contract_address(), owner_address(), repository_name());
+    CACHE.transactions_dir().join(repository_id)
+}
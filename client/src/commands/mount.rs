@@ -1,46 +1,38 @@
 use std::ffi::OsStr;
+use std::time::Duration;
 
-use fuse_mt::FuseMT;
-use web3::types::Address;
 
-use quantumfs::models::repository::Repository;
+use quantumfs::models::transaction::Transaction;
 
-use crate::cache::CACHE;
+use crate::commands::{load_ownership, load_repository, staging_dir};
 use crate::fs::QuantumFS;
+use crate::inodes::InodeFS;
 use crate::settings::SETTINGS;
 
-fn load_repository() -> Repository {
-    let client_address = SETTINGS.get::<Address>("address")
-        .expect("User ethereum address not provided");
-    let contract_address = SETTINGS.get::<Address>("contract")
-        .expect("Contract ethereum address not provided");
-    let web3_url = SETTINGS.get::<String>("web3")
-        .expect("Web3 URL not provided");
-    let ipfs_server = SETTINGS.get::<String>("ipfs-server")
-        .expect("IPFS server not provided");
-    let ipfs_port = SETTINGS.get::<u16>("ipfs-port")
-        .expect("IPFS port not provided");
-    Repository::new(
-        client_address,
-        contract_address,
-        CACHE.data_dir().as_path(),
-        &web3_url,
-        &ipfs_server,
-        ipfs_port,
-    )
-}
-
-pub fn mount() {
+pub fn mount(writable: bool) {
     let mountpoint = SETTINGS.get::<String>("mountpoint")
         .expect("Mount point not provided");
     let repository = load_repository();
-    let qfs = QuantumFS::new(repository)
-        .expect("Failure mounting the file system");
-    let options = ["-o", "ro", "-o", "fsname=qfs"]
+    let (qfs, access) = if writable {
+        let transaction = Transaction::load(staging_dir().as_path())
+            .expect("Failure loading the transaction. Open one with `qfs transaction`");
+        let qfs = QuantumFS::writable(repository, &transaction)
+            .expect("Failure mounting the file system");
+        (qfs, "rw")
+    } else {
+        let qfs = QuantumFS::new(repository)
+            .expect("Failure mounting the file system");
+        (qfs, "ro")
+    };
+    let follow_interval = SETTINGS.get::<u64>("follow_interval")
+        .expect("Invalid follow interval");
+    let qfs = qfs.with_ownership(load_ownership())
+        .with_follow_interval(Duration::from_secs(follow_interval));
+    let options = ["-o", access, "-o", "fsname=qfs"];
+    let options = options
         .iter()
         .map(|o| o.as_ref())
         .collect::<Vec<&OsStr>>();
-    let filesystem = FuseMT::new(qfs, 0);
-    fuse_mt::mount(filesystem, &mountpoint, &options)
+    fuse::mount(InodeFS::new(qfs), &mountpoint, &options)
         .expect("Failure mounting the file system");
 }
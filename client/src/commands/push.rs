@@ -1,3 +1,16 @@
-pub fn push () {
+use quantumfs::models::transaction::Transaction;
 
+use crate::commands::{load_repository, staging_dir};
+
+pub fn push() {
+    let staging_dir = staging_dir();
+    let committed = Transaction::load_commit(staging_dir.as_path())
+        .expect("Failure loading the committed revision")
+        .expect("There is no committed revision to push");
+    let mut repository = load_repository();
+    let tag = repository.push_revision(&committed)
+        .expect("Failure pushing the revision");
+    Transaction::discard_commit(staging_dir.as_path())
+        .expect("Failure cleaning up the committed revision");
+    println!("Pushed root catalog {} as revision {}", tag.hash(), tag.revision());
 }
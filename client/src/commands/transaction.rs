@@ -1,4 +1,18 @@
-pub fn transaction() {
+use quantumfs::models::transaction::Transaction;
 
-}
+use crate::commands::{load_repository, staging_dir};
 
+pub fn transaction() {
+    let mut repository = load_repository();
+    let mut revision = match repository.load_current_revision()
+        .expect("Failure loading the current revision") {
+        Some(current_revision) => current_revision,
+        None => repository.create_revision()
+            .expect("Failure creating the genesis revision"),
+    };
+    let transaction = Transaction::open(&mut revision, staging_dir().as_path())
+        .expect("Failure opening the transaction");
+    println!("Transaction opened on revision {} ({})",
+             transaction.tag().revision(), transaction.tag().hash());
+    println!("Stage your changes in {}", transaction.overlay_dir().display());
+}
@@ -1,26 +1,57 @@
-use std::collections::HashMap;
-use std::ffi::OsString;
-use std::fs::File;
-use std::io::{Read, Seek};
-use std::io::SeekFrom::Start;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, DirBuilder, File, Metadata, OpenOptions, Permissions};
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt, symlink};
 use std::path::{Path, PathBuf};
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
-use fuse_mt::{DirectoryEntry, FileAttr, FilesystemMT, FileType, RequestInfo, ResultData, ResultEmpty, ResultEntry, ResultOpen, ResultReaddir, ResultStatfs, Statfs};
+use fuse_mt::{CallbackResult, CreatedEntry, DirectoryEntry, FileAttr, FilesystemMT, FileType, RequestInfo, ResultCreate, ResultData, ResultEmpty, ResultEntry, ResultOpen, ResultReaddir, ResultSlice, ResultStatfs, ResultWrite, ResultXattr, Statfs, Xattr};
 use libc;
 use time::Timespec;
 
 use quantumfs::errors::QFSError;
-use quantumfs::models::directoryentry::DirectoryEntry as QFSDirent;
+use quantumfs::models::chunk::Chunk;
+use quantumfs::models::directoryentry::{self, DirectoryEntry as QFSDirent};
 use quantumfs::models::repository::Repository;
 use quantumfs::models::revision::Revision;
+use quantumfs::models::transaction::Transaction;
+use quantumfs::operations::xattr::{self, Xattrs};
+
+use crate::overlay::{self, Layer, Overlay};
+use crate::ownership::Ownership;
 
 const TTL: Timespec = Timespec { sec: 240, nsec: 0 };
+const WRITABLE_TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+
+/// Read-only attributes describing where the entries of a revision come from.
+const XATTR_HASH: &str = "user.qfs.hash";
+const XATTR_REVISION: &str = "user.qfs.revision";
+const XATTR_CATALOG: &str = "user.qfs.catalog";
+const XATTR_CHUNKS: &str = "user.qfs.chunks";
 
 
+/// Files of the upper layer are opened locally, while files of the current
+/// revision keep their chunk list so only the chunks being read are fetched.
+/// They also keep the revision they were opened from, so they can still be
+/// read after the mount switched to a newer one.
+enum OpenedFile {
+    Local(File),
+    Lower(Arc<Revision>, Vec<Chunk>),
+}
+
 pub struct QuantumFS {
-    opened_files: RwLock<HashMap<PathBuf, File>>,
-    revision: RwLock<Revision>,
+    opened_files: RwLock<HashMap<u64, Arc<OpenedFile>>>,
+    next_handle: AtomicU64,
+    repository: Repository,
+    revision: RwLock<Arc<Revision>>,
+    overlay: Option<Overlay>,
+    ownership: Ownership,
+    follow_interval: Option<Duration>,
 }
 
 fn get_file_type(dirent: &QFSDirent) -> FileType {
@@ -33,6 +64,85 @@ fn get_file_type(dirent: &QFSDirent) -> FileType {
     kind
 }
 
+fn io_error(error: io::Error) -> libc::c_int {
+    error.raw_os_error().unwrap_or(libc::EIO)
+}
+
+/// Translates the errors of the current revision into the codes every
+/// callback reports to the kernel.
+fn errno(error: QFSError) -> libc::c_int {
+    match error {
+        QFSError::NotFound(_) => libc::ENOENT,
+        QFSError::NotADirectory(_) => libc::ENOTDIR,
+        QFSError::IsADirectory(_) => libc::EISDIR,
+        // symlinks and special files have no contents to read
+        QFSError::NotAFile(_) => libc::EINVAL,
+        QFSError::Io(error) => io_error(error),
+        QFSError::InvalidHash(_) |
+        QFSError::Storage(_, _) |
+        QFSError::Ledger(_, _) |
+        QFSError::Database(_) |
+        QFSError::UnsupportedSchema(_) |
+        QFSError::Integrity { .. } |
+        QFSError::Conflict { .. } |
+        QFSError::Other(_) => libc::EIO,
+    }
+}
+
+fn dirent_attr(dirent: &QFSDirent, ownership: &Ownership) -> FileAttr {
+    FileAttr {
+        size: dirent.size as u64,
+        blocks: (1 + dirent.size / 512) as u64,
+        atime: Timespec { sec: dirent.mtime, nsec: 0 },
+        mtime: Timespec { sec: dirent.mtime, nsec: 0 },
+        ctime: Timespec { sec: dirent.mtime, nsec: 0 },
+        crtime: Timespec { sec: dirent.mtime, nsec: 0 },
+        kind: get_file_type(dirent),
+        perm: dirent.mode as u16,
+        nlink: dirent.links as u32,
+        uid: ownership.uid(dirent),
+        gid: ownership.gid(dirent),
+        rdev: 1,
+        flags: dirent.flags as u32,
+    }
+}
+
+fn metadata_attr(metadata: &Metadata) -> FileAttr {
+    FileAttr {
+        size: metadata.len(),
+        blocks: metadata.blocks(),
+        atime: Timespec { sec: metadata.atime(), nsec: metadata.atime_nsec() as i32 },
+        mtime: Timespec { sec: metadata.mtime(), nsec: metadata.mtime_nsec() as i32 },
+        ctime: Timespec { sec: metadata.ctime(), nsec: metadata.ctime_nsec() as i32 },
+        crtime: Timespec { sec: metadata.mtime(), nsec: metadata.mtime_nsec() as i32 },
+        kind: overlay::get_file_type(&metadata.file_type()),
+        perm: (metadata.mode() & 0o7777) as u16,
+        nlink: metadata.nlink() as u32,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        rdev: metadata.rdev() as u32,
+        flags: 0,
+    }
+}
+
+/// Answers a size probe when `size` is 0, as getxattr(2) and listxattr(2) do.
+fn xattr_reply(data: Vec<u8>, size: u32) -> ResultXattr {
+    if size == 0 {
+        Ok(Xattr::Size(data.len() as u32))
+    } else if data.len() > size as usize {
+        Err(libc::ERANGE)
+    } else {
+        Ok(Xattr::Data(data))
+    }
+}
+
+fn to_timespec(time: Option<Timespec>) -> libc::timespec {
+    match time {
+        Some(time) => libc::timespec { tv_sec: time.sec, tv_nsec: time.nsec as libc::c_long },
+        None => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+    }
+}
+
 impl FilesystemMT for QuantumFS {
     fn init(&self, _req: RequestInfo) -> ResultEmpty {
         Ok(())
@@ -43,101 +153,220 @@ impl FilesystemMT for QuantumFS {
     }
 
     fn getattr(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>) -> ResultEntry {
-        let mut revision = self.revision.write().unwrap();
-        let path = path.to_str().unwrap();
-        match revision.lookup(path) {
-            Err(_) => Err(1),
-            Ok(dirent) => {
-                let kind = get_file_type(&dirent);
-                Ok((TTL, FileAttr {
-                    size: dirent.size as u64,
-                    blocks: (1 + dirent.size / 512) as u64,
-                    atime: Timespec { sec: dirent.mtime, nsec: 0 },
-                    mtime: Timespec { sec: dirent.mtime, nsec: 0 },
-                    ctime: Timespec { sec: dirent.mtime, nsec: 0 },
-                    crtime: Timespec { sec: dirent.mtime, nsec: 0 },
-                    kind,
-                    perm: dirent.mode as u16,
-                    nlink: 1,
-                    uid: users::get_current_uid(),
-                    gid: users::get_current_gid(),
-                    rdev: 1,
-                    flags: dirent.flags as u32,
-                }))
-            }
+        match self.resolve(path) {
+            Layer::Hidden => return Err(libc::ENOENT),
+            Layer::Upper(upper) => return fs::symlink_metadata(upper)
+                .map(|metadata| (self.ttl(), metadata_attr(&metadata)))
+                .map_err(io_error),
+            Layer::Lower => {}
         }
+        let revision = self.revision();
+        let path = path.to_str().unwrap();
+        revision.lookup(path)
+            .map(|dirent| (self.ttl(), dirent_attr(&dirent, &self.ownership)))
+            .map_err(errno)
     }
 
+    fn chmod(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>, mode: u32) -> ResultEmpty {
+        let upper = self.copy_up(path)?;
+        fs::set_permissions(upper, Permissions::from_mode(mode))
+            .map_err(io_error)
+    }
 
+    fn truncate(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>, size: u64) -> ResultEmpty {
+        let upper = self.copy_up(path)?;
+        OpenOptions::new()
+            .write(true)
+            .open(upper)
+            .and_then(|file| file.set_len(size))
+            .map_err(io_error)
+    }
+
+    fn utimens(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>, atime: Option<Timespec>, mtime: Option<Timespec>) -> ResultEmpty {
+        let upper = self.copy_up(path)?;
+        let upper = CString::new(upper.as_os_str().as_bytes()).map_err(|_| libc::EINVAL)?;
+        let times = [to_timespec(atime), to_timespec(mtime)];
+        let result = unsafe {
+            libc::utimensat(libc::AT_FDCWD, upper.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW)
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(io_error(io::Error::last_os_error())),
+        }
+    }
 
     fn readlink(&self, _req: RequestInfo, path: &Path) -> ResultData {
-        match self.revision.write().unwrap().lookup(path.to_str().unwrap()) {
-            Err(_) => Err(libc::ENOENT),
-            Ok(dirent) => {
-                if dirent.is_symlink() {
-                    Ok(Vec::from(dirent.symlink.as_bytes()))
-                } else {
-                    Err(libc::ENOLINK)
-                }
-            }
+        match self.resolve(path) {
+            Layer::Hidden => return Err(libc::ENOENT),
+            Layer::Upper(upper) => return fs::read_link(upper)
+                .map(|target| Vec::from(target.as_os_str().as_bytes()))
+                .map_err(io_error),
+            Layer::Lower => {}
+        }
+        let dirent = self.revision().lookup(path.to_str().unwrap())
+            .map_err(errno)?;
+        if dirent.is_symlink() {
+            Ok(Vec::from(dirent.symlink.as_bytes()))
+        } else {
+            Err(libc::EINVAL)
         }
     }
 
-    fn open(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
-        match self.revision.write().unwrap().get_file(path.to_str().unwrap()) {
-            Err(_) => Err(libc::ENOENT),
-            Ok(file) => {
-                self.opened_files.write().unwrap().insert(PathBuf::from(path), file);
-                Ok((0, libc::O_RDONLY as u32))
-            }
+    fn mkdir(&self, _req: RequestInfo, parent: &Path, name: &OsStr, mode: u32) -> ResultEntry {
+        let (overlay, path) = self.prepare_entry(parent, name)?;
+        let upper = overlay.upper_path(&path);
+        let replaced = overlay.remove_whiteout(&path).map_err(io_error)?;
+        DirBuilder::new()
+            .mode(mode)
+            .create(upper.as_path())
+            .map_err(io_error)?;
+        if replaced {
+            overlay.mark_opaque(&path).map_err(io_error)?;
         }
+        self.upper_entry(upper.as_path())
     }
 
-    fn read(&self, _req: RequestInfo, path: &Path, _fh: u64, offset: u64, size: u32, result: impl FnOnce(Result<&[u8], i32>)) {
-        if let Some(mut file) = self.opened_files.read().unwrap().get(path) {
-            let size = size as usize;
-            let mut buffer = Vec::with_capacity(size);
-            file.seek(Start(offset)).unwrap();
-            file.read_exact(&mut buffer[0..size]).unwrap();
-            result(Ok(&buffer));
-        } else {
-            result(Err(libc::ENOENT));
+    fn unlink(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+        let path = parent.join(name);
+        match self.resolve(&path) {
+            Layer::Hidden => return Err(libc::ENOENT),
+            Layer::Upper(upper) => fs::remove_file(upper).map_err(io_error)?,
+            Layer::Lower => if !self.exists_in_lower(&path) {
+                return Err(libc::ENOENT);
+            },
         }
+        self.whiteout_lower(&path)
     }
 
-    fn release(&self, _req: RequestInfo, path: &Path, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> ResultEmpty {
-        self.opened_files.write().unwrap().remove(path);
-        Ok(())
+    fn rmdir(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+        let path = parent.join(name);
+        if !self.list(&path)?.is_empty() {
+            return Err(libc::ENOTEMPTY);
+        }
+        match self.resolve(&path) {
+            Layer::Hidden => return Err(libc::ENOENT),
+            // only whiteouts may remain in an empty upper directory
+            Layer::Upper(upper) => fs::remove_dir_all(upper).map_err(io_error)?,
+            Layer::Lower => {}
+        }
+        self.whiteout_lower(&path)
     }
 
-    fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
-        let mut revision = self.revision.write().unwrap();
-        match revision.lookup(path.to_str().unwrap()) {
-            Err(_) => Err(1),
-            Ok(dirent) => {
-                if dirent.is_directory() {
-                    return Ok((0, dirent.flags as u32))
+    fn symlink(&self, _req: RequestInfo, parent: &Path, name: &OsStr, target: &Path) -> ResultEntry {
+        let (overlay, path) = self.prepare_entry(parent, name)?;
+        let upper = overlay.upper_path(&path);
+        overlay.remove_whiteout(&path).map_err(io_error)?;
+        symlink(target, upper.as_path()).map_err(io_error)?;
+        self.upper_entry(upper.as_path())
+    }
+
+    fn rename(&self, _req: RequestInfo, parent: &Path, name: &OsStr, newparent: &Path, newname: &OsStr) -> ResultEmpty {
+        let path = parent.join(name);
+        let overlay = self.writable_overlay()?;
+        let lower = self.revision().lookup(path.to_str().unwrap()).ok();
+        match lower {
+            // the kernel falls back to copying when directories cannot be moved
+            Some(ref dirent) if dirent.is_directory() => return Err(libc::EXDEV),
+            _ => {}
+        }
+        let upper = self.copy_up(&path)?;
+        let (_, new_path) = self.prepare_entry(newparent, newname)?;
+        overlay.remove_whiteout(&new_path).map_err(io_error)?;
+        fs::rename(upper, overlay.upper_path(&new_path)).map_err(io_error)?;
+        self.whiteout_lower(&path)
+    }
+
+    fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        let write_intent = (flags as i32 & libc::O_ACCMODE) != libc::O_RDONLY
+            || (flags as i32 & libc::O_TRUNC) != 0;
+        if write_intent && self.overlay.is_none() {
+            return Err(libc::EROFS);
+        }
+        let file = if write_intent {
+            let upper = self.copy_up(path)?;
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(upper)
+                .map(OpenedFile::Local)
+                .map_err(io_error)?
+        } else {
+            match self.resolve(path) {
+                Layer::Hidden => return Err(libc::ENOENT),
+                Layer::Upper(upper) => File::open(upper).map(OpenedFile::Local).map_err(io_error)?,
+                Layer::Lower => {
+                    let revision = self.revision();
+                    let chunks = revision.file_chunks(path.to_str().unwrap())
+                        .map_err(errno)?;
+                    if let Some(cache) = revision.cache() {
+                        for chunk in chunks.iter() {
+                            cache.pin(&chunk.hash).map_err(errno)?;
+                        }
+                    }
+                    OpenedFile::Lower(revision, chunks)
                 }
-                Err(libc::ENOENT)
             }
+        };
+        // no FOPEN_* flags, so the kernel drops the cached pages of the file
+        Ok((self.add_handle(file), 0))
+    }
+
+    fn read(&self, _req: RequestInfo, _path: &Path, fh: u64, offset: u64, size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
+        match self.read_data(fh, offset, size) {
+            Ok(data) => callback(Ok(&data)),
+            Err(error) => callback(Err(error)),
         }
     }
 
-    fn readdir(&self, _req: RequestInfo, path: &Path, _fh: u64) -> ResultReaddir {
-        match self.revision.write().unwrap().list_directory(path.to_str().unwrap()) {
-            Err(_) => Err(libc::ENOENT),
-            Ok(dirents) => {
-                Ok(dirents.iter().map(|dirent| {
-                    DirectoryEntry {
-                        name: OsString::from(dirent.name.as_str()),
-                        kind: get_file_type(&dirent),
-                    }
-                }).collect())
+    fn write(&self, _req: RequestInfo, _path: &Path, fh: u64, offset: u64, data: Vec<u8>, _flags: u32) -> ResultWrite {
+        let file = self.opened_files.read().unwrap().get(&fh).cloned()
+            .ok_or(libc::EBADF)?;
+        match *file {
+            OpenedFile::Local(ref file) => {
+                file.write_all_at(&data, offset).map_err(io_error)?;
+                Ok(data.len() as u32)
+            }
+            OpenedFile::Lower(..) => Err(libc::EBADF),
+        }
+    }
+
+    fn release(&self, _req: RequestInfo, _path: &Path, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> ResultEmpty {
+        match self.opened_files.write().unwrap().remove(&fh) {
+            Some(file) => {
+                self.unpin(&file);
+                Ok(())
             }
+            None => Err(libc::EBADF),
         }
     }
 
-    fn statfs(&self, _req: RequestInfo, path: &Path) -> ResultStatfs {
+    fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
+        match self.resolve(path) {
+            Layer::Hidden => return Err(libc::ENOENT),
+            Layer::Upper(ref upper) if upper.is_dir() => return Ok((0, 0)),
+            Layer::Upper(_) => return Err(libc::ENOTDIR),
+            Layer::Lower => {}
+        }
+        let dirent = self.revision().lookup(path.to_str().unwrap())
+            .map_err(errno)?;
+        if dirent.is_directory() {
+            Ok((0, dirent.flags as u32))
+        } else {
+            Err(libc::ENOTDIR)
+        }
+    }
+
+    fn readdir(&self, _req: RequestInfo, path: &Path, _fh: u64) -> ResultReaddir {
+        let entries = self.list(path)?;
+        Ok(entries.into_iter().map(|(name, kind)| {
+            DirectoryEntry {
+                name,
+                kind,
+            }
+        }).collect())
+    }
+
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
         Ok(Statfs {
             blocks: 0,
             bfree: 0,
@@ -149,6 +378,42 @@ impl FilesystemMT for QuantumFS {
             frsize: 512
         })
     }
+
+    fn getxattr(&self, _req: RequestInfo, path: &Path, name: &OsStr, size: u32) -> ResultXattr {
+        let name = name.to_str().ok_or(libc::ENODATA)?;
+        let value = self.xattrs(path)?.remove(name).ok_or(libc::ENODATA)?;
+        xattr_reply(value, size)
+    }
+
+    fn listxattr(&self, _req: RequestInfo, path: &Path, size: u32) -> ResultXattr {
+        let mut names = Vec::new();
+        for name in self.xattrs(path)?.keys() {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        xattr_reply(names, size)
+    }
+
+    fn create(&self, _req: RequestInfo, parent: &Path, name: &OsStr, mode: u32, flags: u32) -> ResultCreate {
+        let (overlay, path) = self.prepare_entry(parent, name)?;
+        let upper = overlay.upper_path(&path);
+        overlay.remove_whiteout(&path).map_err(io_error)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate((flags as i32 & libc::O_TRUNC) != 0)
+            .mode(mode)
+            .open(upper.as_path())
+            .map_err(io_error)?;
+        let (ttl, attr) = self.upper_entry(upper.as_path())?;
+        Ok(CreatedEntry {
+            ttl,
+            attr,
+            fh: self.add_handle(OpenedFile::Local(file)),
+            flags: 0,
+        })
+    }
 }
 
 impl QuantumFS {
@@ -159,7 +424,260 @@ impl QuantumFS {
         };
         Ok(Self {
             opened_files: RwLock::new(HashMap::new()),
-            revision: RwLock::new(revision),
+            next_handle: AtomicU64::new(1),
+            repository,
+            revision: RwLock::new(Arc::new(revision)),
+            overlay: None,
+            ownership: Ownership::Preserve,
+            follow_interval: None,
         })
     }
+
+    pub fn writable(repository: Repository, transaction: &Transaction) -> Result<Self, QFSError> {
+        let revision = repository.open_revision(transaction.tag())?;
+        Ok(Self {
+            opened_files: RwLock::new(HashMap::new()),
+            next_handle: AtomicU64::new(1),
+            repository,
+            revision: RwLock::new(Arc::new(revision)),
+            overlay: Some(Overlay::new(transaction.overlay_dir().as_path())),
+            ownership: Ownership::Preserve,
+            follow_interval: None,
+        })
+    }
+
+    /// Makes read-only mounts check the ledger for new revisions every
+    /// `interval`. Writable mounts stay on the revision of their transaction.
+    pub fn with_follow_interval(mut self, interval: Duration) -> Self {
+        if self.overlay.is_none() && interval > Duration::from_secs(0) {
+            self.follow_interval = Some(interval);
+        }
+        self
+    }
+
+    pub fn follow_interval(&self) -> Option<Duration> {
+        self.follow_interval
+    }
+
+    /// Switches to the last revision of the ledger if it is newer than the
+    /// one being served, returning the revision it replaced. Files already
+    /// open keep reading from the revision they were opened from.
+    pub fn refresh(&self) -> Result<Option<Arc<Revision>>, QFSError> {
+        let tag = self.repository.ledger().current_revision()?;
+        if tag.revision() <= self.revision().revision() {
+            return Ok(None);
+        }
+        let revision = self.repository.open_revision(&tag)?;
+        // fail before switching if the new root catalog cannot be loaded
+        revision.retrieve_root_catalog()?;
+        let previous = mem::replace(&mut *self.revision.write().unwrap(), Arc::new(revision));
+        Ok(Some(previous))
+    }
+
+    /// Whether the entry at `path` differs between `previous` and the
+    /// revision being served, including appearing or disappearing.
+    pub fn changed_since(&self, previous: &Revision, path: &Path) -> bool {
+        let path = path.to_str().unwrap();
+        previous.lookup(path).ok() != self.revision().lookup(path).ok()
+    }
+
+    fn revision(&self) -> Arc<Revision> {
+        self.revision.read().unwrap().clone()
+    }
+
+    /// Chooses how the owners recorded in the revision are reported.
+    pub fn with_ownership(mut self, ownership: Ownership) -> Self {
+        self.ownership = ownership;
+        self
+    }
+
+    /// Stable inode of an entry, shared by the entries of the revision and
+    /// their copies in the upper layer, along with the key it derives from.
+    pub fn inode(&self, path: &Path) -> (u64, String) {
+        let path = path.to_str().unwrap();
+        if let Layer::Lower = self.resolve(Path::new(path)) {
+            if let Ok(dirent) = self.revision().lookup(path) {
+                return (dirent.inode(), dirent.inode_key());
+            }
+        }
+        (directoryentry::path_inode(path), directoryentry::path_inode_key(path))
+    }
+
+    /// Reads from a file previously opened with the given handle. Reads near
+    /// the end of the file return less data than requested, and none past it.
+    pub fn read_data(&self, fh: u64, offset: u64, size: u32) -> ResultData {
+        let file = self.opened_files.read().unwrap().get(&fh).cloned()
+            .ok_or(libc::EBADF)?;
+        match *file {
+            OpenedFile::Local(ref file) => {
+                let mut buffer = vec![0; size as usize];
+                let mut read = 0;
+                while read < buffer.len() {
+                    match file.read_at(&mut buffer[read..], offset + read as u64) {
+                        Ok(0) => break,
+                        Ok(count) => read += count,
+                        Err(ref error) if error.kind() == ErrorKind::Interrupted => {}
+                        Err(error) => return Err(io_error(error)),
+                    }
+                }
+                buffer.truncate(read);
+                Ok(buffer)
+            }
+            OpenedFile::Lower(ref revision, ref chunks) => {
+                revision.read_chunks(chunks, offset, size as usize)
+                    .map_err(errno)
+            }
+        }
+    }
+
+    /// Registers an opened file under a new handle, so the same path can be
+    /// opened many times at once.
+    fn add_handle(&self, file: OpenedFile) -> u64 {
+        let fh = self.next_handle.fetch_add(1, Ordering::SeqCst);
+        self.opened_files.write().unwrap().insert(fh, Arc::new(file));
+        fh
+    }
+
+    /// Followed mounts have the kernel look their entries up again once they
+    /// may have changed, as it cannot be told which ones did.
+    fn ttl(&self) -> Timespec {
+        match (&self.overlay, self.follow_interval) {
+            (Some(_), _) => WRITABLE_TTL,
+            (None, Some(interval)) if (interval.as_secs() as i64) < TTL.sec =>
+                Timespec { sec: interval.as_secs() as i64, nsec: interval.subsec_nanos() as i32 },
+            (None, _) => TTL,
+        }
+    }
+
+    /// Lets the cache evict the chunks of a file of the revision once it is closed.
+    fn unpin(&self, file: &OpenedFile) {
+        if let OpenedFile::Lower(revision, chunks) = file {
+            if let Some(cache) = revision.cache() {
+                for chunk in chunks.iter() {
+                    cache.unpin(&chunk.hash).ok();
+                }
+            }
+        }
+    }
+
+    fn writable_overlay(&self) -> Result<&Overlay, libc::c_int> {
+        self.overlay.as_ref().ok_or(libc::EROFS)
+    }
+
+    fn resolve(&self, path: &Path) -> Layer {
+        match self.overlay {
+            Some(ref overlay) => overlay.resolve(path),
+            None => Layer::Lower,
+        }
+    }
+
+    fn exists_in_lower(&self, path: &Path) -> bool {
+        self.revision().lookup(path.to_str().unwrap()).is_ok()
+    }
+
+    fn whiteout_lower(&self, path: &Path) -> ResultEmpty {
+        let overlay = self.writable_overlay()?;
+        if self.exists_in_lower(path) {
+            if let Some(parent) = path.parent() {
+                self.copy_up(parent)?;
+            }
+            overlay.whiteout(path).map_err(io_error)?;
+        }
+        Ok(())
+    }
+
+    /// Makes sure the parent of a new entry lives in the upper layer.
+    fn prepare_entry(&self, parent: &Path, name: &OsStr) -> Result<(&Overlay, PathBuf), libc::c_int> {
+        let overlay = self.writable_overlay()?;
+        self.copy_up(parent)?;
+        Ok((overlay, parent.join(name)))
+    }
+
+    fn upper_entry(&self, upper: &Path) -> ResultEntry {
+        fs::symlink_metadata(upper)
+            .map(|metadata| (self.ttl(), metadata_attr(&metadata)))
+            .map_err(io_error)
+    }
+
+    /// Copies an entry of the current revision into the upper layer, so it
+    /// can be modified. Directories are copied without their contents.
+    fn copy_up(&self, path: &Path) -> Result<PathBuf, libc::c_int> {
+        let overlay = self.writable_overlay()?;
+        match overlay.resolve(path) {
+            Layer::Upper(upper) => return Ok(upper),
+            Layer::Hidden => return Err(libc::ENOENT),
+            Layer::Lower => {}
+        }
+        if let Some(parent) = path.parent() {
+            self.copy_up(parent)?;
+        }
+        let revision = self.revision();
+        let dirent = revision.lookup(path.to_str().unwrap())
+            .map_err(errno)?;
+        let upper = overlay.upper_path(path);
+        if dirent.is_directory() {
+            fs::create_dir(upper.as_path()).map_err(io_error)?;
+        } else if dirent.is_symlink() {
+            return symlink(dirent.symlink.as_str(), upper.as_path())
+                .map(|_| upper)
+                .map_err(io_error);
+        } else {
+            let chunks = revision.file_chunks(path.to_str().unwrap())
+                .map_err(errno)?;
+            let mut target = File::create(upper.as_path()).map_err(io_error)?;
+            for chunk in chunks.iter() {
+                let mut source = revision.get_object(&chunk.hash)
+                    .map_err(errno)?;
+                io::copy(&mut source, &mut target).map_err(io_error)?;
+            }
+        }
+        fs::set_permissions(upper.as_path(), Permissions::from_mode(dirent.mode as u32 & 0o7777))
+            .map_err(io_error)?;
+        Ok(upper)
+    }
+
+    /// Extended attributes of an entry. Entries of the revision also carry
+    /// the virtual `user.qfs.*` attributes.
+    fn xattrs(&self, path: &Path) -> Result<Xattrs, libc::c_int> {
+        match self.resolve(path) {
+            Layer::Hidden => return Err(libc::ENOENT),
+            Layer::Upper(upper) => return xattr::read(upper.as_path()).map_err(io_error),
+            Layer::Lower => {}
+        }
+        let path = path.to_str().unwrap();
+        let revision = self.revision();
+        let dirent = revision.lookup(path).map_err(errno)?;
+        let mut xattrs = dirent.xattrs.clone();
+        xattrs.insert(XATTR_HASH.to_string(), dirent.hash.to_string().into_bytes());
+        xattrs.insert(XATTR_REVISION.to_string(), revision.revision().to_string().into_bytes());
+        let catalog = revision.retrieve_catalog_for_path(path).map_err(errno)?.hash().to_string();
+        xattrs.insert(XATTR_CATALOG.to_string(), catalog.into_bytes());
+        if dirent.is_file() {
+            let chunks = revision.file_chunks(path).map_err(errno)?.iter()
+                .map(|chunk| format!("{} {} {}\n", chunk.offset, chunk.size, chunk.hash))
+                .collect::<String>();
+            xattrs.insert(XATTR_CHUNKS.to_string(), chunks.into_bytes());
+        }
+        Ok(xattrs)
+    }
+
+    fn list(&self, path: &Path) -> Result<BTreeMap<OsString, FileType>, libc::c_int> {
+        let upper = match self.resolve(path) {
+            Layer::Hidden => return Err(libc::ENOENT),
+            Layer::Upper(upper) => Some(upper),
+            Layer::Lower => None,
+        };
+        let lower = match self.revision().list_directory(path.to_str().unwrap()) {
+            Ok(dirents) => dirents.iter().map(|dirent| {
+                (OsString::from(dirent.name.as_str()), get_file_type(dirent))
+            }).collect(),
+            Err(QFSError::NotFound(_)) | Err(QFSError::NotADirectory(_)) if upper.is_some() => BTreeMap::new(),
+            Err(error) => return Err(errno(error)),
+        };
+        match (upper, &self.overlay) {
+            (Some(upper), Some(overlay)) => overlay.merge_listing(upper.as_path(), lower)
+                .map_err(io_error),
+            _ => Ok(lower),
+        }
+    }
 }
@@ -0,0 +1,598 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use fuse::{Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request};
+use fuse_mt::{FileAttr, FilesystemMT, RequestInfo, Xattr};
+use threadpool::ThreadPool;
+use time::Timespec;
+
+use quantumfs::errors::QFSError;
+use quantumfs::models::directoryentry::ROOT_INODE;
+
+use crate::fs::QuantumFS;
+
+/// Workers serving the callbacks that only read the file system.
+const THREADS: usize = 8;
+
+fn request_info(req: &Request) -> RequestInfo {
+    RequestInfo {
+        unique: req.unique(),
+        uid: req.uid(),
+        gid: req.gid(),
+        pid: req.pid(),
+    }
+}
+
+fn fuse_attr(attr: FileAttr, ino: u64) -> fuse::FileAttr {
+    fuse::FileAttr {
+        ino,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime: attr.atime,
+        mtime: attr.mtime,
+        ctime: attr.ctime,
+        crtime: attr.crtime,
+        kind: attr.kind,
+        perm: attr.perm,
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        rdev: attr.rdev,
+        flags: attr.flags,
+    }
+}
+
+fn reply_empty(result: Result<(), libc::c_int>, reply: ReplyEmpty) {
+    match result {
+        Ok(()) => reply.ok(),
+        Err(error) => reply.error(error),
+    }
+}
+
+fn reply_xattr(result: Result<Xattr, libc::c_int>, reply: ReplyXattr) {
+    match result {
+        Ok(Xattr::Size(size)) => reply.size(size),
+        Ok(Xattr::Data(data)) => reply.data(&data),
+        Err(error) => reply.error(error),
+    }
+}
+
+/// An inode known to the kernel, with the path it was last looked up by,
+/// the key its number derives from, how many lookups the kernel has not
+/// forgotten yet and its generation. The generation is bumped when a
+/// followed revision changes the entry, so that the next lookup of the entry
+/// tells the kernel it is a different inode than the one it knew.
+struct Inode {
+    path: PathBuf,
+    key: String,
+    lookups: u64,
+    generation: u64,
+}
+
+/// Inodes known to the kernel. Numbers derive from the keys of the entries,
+/// and a key whose number is taken by another one gets the next free number.
+struct InodeTable {
+    inodes: HashMap<u64, Inode>,
+    keys: HashMap<String, u64>,
+}
+
+impl InodeTable {
+    fn new(root_key: String) -> Self {
+        let mut inodes = HashMap::new();
+        let mut keys = HashMap::new();
+        keys.insert(root_key.clone(), ROOT_INODE);
+        inodes.insert(ROOT_INODE, Inode { path: PathBuf::from("/"), key: root_key, lookups: 1, generation: 0 });
+        Self { inodes, keys }
+    }
+
+    /// Number of the inode with the given key, if the kernel knows it, or
+    /// the first free number from the one derived from the key otherwise.
+    fn number(&self, derived: u64, key: &str) -> u64 {
+        if let Some(&ino) = self.keys.get(key) {
+            return ino;
+        }
+        let mut ino = derived;
+        while self.inodes.contains_key(&ino) {
+            ino = if ino == u64::MAX { ROOT_INODE + 1 } else { ino + 1 };
+        }
+        ino
+    }
+
+    /// Records a lookup and returns the number and generation of the inode.
+    fn remember(&mut self, path: PathBuf, derived: u64, key: String) -> (u64, u64) {
+        let ino = self.number(derived, key.as_str());
+        self.keys.insert(key.clone(), ino);
+        let inode = self.inodes.entry(ino)
+            .or_insert(Inode { path: PathBuf::new(), key, lookups: 0, generation: 0 });
+        // hardlinks share an inode, and any of their paths serves them
+        inode.path = path;
+        inode.lookups += 1;
+        (ino, inode.generation)
+    }
+
+    /// Bumps the generation of the given inodes, if the kernel still knows them.
+    fn renew(&mut self, inodes: &[u64]) {
+        for ino in inodes {
+            if let Some(inode) = self.inodes.get_mut(ino) {
+                inode.generation += 1;
+            }
+        }
+    }
+
+    fn forget(&mut self, ino: u64, nlookup: u64) {
+        let forgotten = match self.inodes.get_mut(&ino) {
+            Some(inode) => {
+                inode.lookups = inode.lookups.saturating_sub(nlookup);
+                inode.lookups == 0
+            }
+            None => false,
+        };
+        if forgotten {
+            let inode = self.inodes.remove(&ino).unwrap();
+            self.keys.remove(&inode.key);
+        }
+    }
+}
+
+/// State shared with the workers of the pool.
+struct Inodes {
+    target: QuantumFS,
+    inodes: Mutex<InodeTable>,
+    listings: Mutex<HashMap<u64, Arc<Vec<fuse_mt::DirectoryEntry>>>>,
+    next_listing: AtomicU64,
+}
+
+/// Serves QuantumFS through the inode-based FUSE protocol, using the stable
+/// inodes of the catalog entries instead of numbering paths as they are
+/// looked up. The same revision thus shows the same inodes across mounts.
+///
+/// Callbacks reading the file system run on a pool of workers, so parallel
+/// lookups and reads do not wait for each other, while changes are applied
+/// in the order the kernel sends them.
+pub struct InodeFS {
+    shared: Arc<Inodes>,
+    pool: ThreadPool,
+}
+
+macro_rules! get_path {
+    ($s:expr, $ino:expr, $reply:expr) => {
+        match $s.path($ino) {
+            Some(path) => path,
+            None => {
+                $reply.error(libc::ENOENT);
+                return;
+            }
+        }
+    }
+}
+
+impl Inodes {
+    fn path(&self, ino: u64) -> Option<PathBuf> {
+        self.inodes.lock().unwrap().inodes.get(&ino).map(|inode| inode.path.clone())
+    }
+
+    /// Records a lookup of `path` by the kernel and returns its inode and
+    /// generation.
+    fn remember(&self, path: PathBuf) -> (u64, u64) {
+        let (derived, key) = self.target.inode(path.as_path());
+        self.inodes.lock().unwrap().remember(path, derived, key)
+    }
+
+    fn reply_entry(&self, path: PathBuf, result: Result<(Timespec, FileAttr), libc::c_int>, reply: ReplyEntry) {
+        match result {
+            Ok((ttl, attr)) => {
+                let (ino, generation) = self.remember(path);
+                reply.entry(&ttl, &fuse_attr(attr, ino), generation);
+            }
+            Err(error) => reply.error(error),
+        }
+    }
+
+    /// Switches to the last revision and renews the inodes whose entries it
+    /// changed.
+    ///
+    /// The kernel caches are not invalidated, since FUSE 0.3 cannot notify
+    /// the kernel: changed entries only show once their TTL, capped to the
+    /// follow interval, expires and they are looked up again. Pages cached
+    /// for a file are dropped the next time it is opened, while files kept
+    /// open go on reading the revision they were opened from.
+    fn refresh(&self) -> Result<(), QFSError> {
+        let previous = match self.target.refresh()? {
+            Some(previous) => previous,
+            None => return Ok(()),
+        };
+        let known: Vec<(u64, PathBuf)> = self.inodes.lock().unwrap().inodes.iter()
+            .filter(|(&ino, _)| ino != ROOT_INODE)
+            .map(|(&ino, inode)| (ino, inode.path.clone()))
+            .collect();
+        let changed: Vec<u64> = known.into_iter()
+            .filter(|(_, path)| self.target.changed_since(&previous, path.as_path()))
+            .map(|(ino, _)| ino)
+            .collect();
+        self.inodes.lock().unwrap().renew(&changed);
+        Ok(())
+    }
+}
+
+impl InodeFS {
+    pub fn new(target: QuantumFS) -> Self {
+        let (_, root_key) = target.inode(Path::new("/"));
+        Self {
+            shared: Arc::new(Inodes {
+                target,
+                inodes: Mutex::new(InodeTable::new(root_key)),
+                listings: Mutex::new(HashMap::new()),
+                next_listing: AtomicU64::new(1),
+            }),
+            pool: ThreadPool::new(THREADS),
+        }
+    }
+
+    /// Polls the ledger in the background while the file system is mounted,
+    /// switching to every new revision as it is published.
+    fn follow(shared: Weak<Inodes>, interval: Duration) {
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+            if let Err(error) = shared.refresh() {
+                error!("Failure following the last revision: {}", error);
+            }
+        });
+    }
+
+    /// Runs a callback on the pool, handing it the shared state.
+    fn spawn<F: FnOnce(&Inodes) + Send + 'static>(&self, callback: F) {
+        let shared = self.shared.clone();
+        self.pool.execute(move || callback(&shared));
+    }
+}
+
+impl Filesystem for InodeFS {
+    fn init(&mut self, req: &Request) -> Result<(), libc::c_int> {
+        self.shared.target.init(request_info(req))?;
+        if let Some(interval) = self.shared.target.follow_interval() {
+            Self::follow(Arc::downgrade(&self.shared), interval);
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self, req: &Request) {
+        self.pool.join();
+        self.shared.target.destroy(request_info(req));
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let info = request_info(req);
+        let name = name.to_owned();
+        self.spawn(move |shared| {
+            let path = get_path!(shared, parent, reply).join(name);
+            let result = shared.target.getattr(info, path.as_path(), None);
+            shared.reply_entry(path, result, reply);
+        });
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        if ino == ROOT_INODE {
+            return;
+        }
+        self.shared.inodes.lock().unwrap().forget(ino, nlookup);
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        let info = request_info(req);
+        self.spawn(move |shared| {
+            let path = get_path!(shared, ino, reply);
+            match shared.target.getattr(info, path.as_path(), None) {
+                Ok((ttl, attr)) => reply.attr(&ttl, &fuse_attr(attr, ino)),
+                Err(error) => reply.error(error),
+            }
+        });
+    }
+
+    fn setattr(&mut self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>,
+               size: Option<u64>, atime: Option<Timespec>, mtime: Option<Timespec>, fh: Option<u64>,
+               _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>,
+               _flags: Option<u32>, reply: ReplyAttr) {
+        let path = get_path!(self.shared, ino, reply);
+        let target = &self.shared.target;
+        let info = request_info(req);
+        let result = mode.map_or(Ok(()), |mode| target.chmod(info, path.as_path(), fh, mode))
+            .and_then(|_| match (uid, gid) {
+                (None, None) => Ok(()),
+                _ => target.chown(info, path.as_path(), fh, uid, gid),
+            })
+            .and_then(|_| size.map_or(Ok(()), |size| target.truncate(info, path.as_path(), fh, size)))
+            .and_then(|_| match (atime, mtime) {
+                (None, None) => Ok(()),
+                _ => target.utimens(info, path.as_path(), fh, atime, mtime),
+            })
+            .and_then(|_| target.getattr(info, path.as_path(), fh));
+        match result {
+            Ok((ttl, attr)) => reply.attr(&ttl, &fuse_attr(attr, ino)),
+            Err(error) => reply.error(error),
+        }
+    }
+
+    fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        let info = request_info(req);
+        self.spawn(move |shared| {
+            let path = get_path!(shared, ino, reply);
+            match shared.target.readlink(info, path.as_path()) {
+                Ok(data) => reply.data(&data),
+                Err(error) => reply.error(error),
+            }
+        });
+    }
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        let parent_path = get_path!(self.shared, parent, reply);
+        let result = self.shared.target.mkdir(request_info(req), parent_path.as_path(), name, mode);
+        self.shared.reply_entry(parent_path.join(name), result, reply);
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let parent_path = get_path!(self.shared, parent, reply);
+        reply_empty(self.shared.target.unlink(request_info(req), parent_path.as_path(), name), reply);
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let parent_path = get_path!(self.shared, parent, reply);
+        reply_empty(self.shared.target.rmdir(request_info(req), parent_path.as_path(), name), reply);
+    }
+
+    fn symlink(&mut self, req: &Request, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+        let parent_path = get_path!(self.shared, parent, reply);
+        let result = self.shared.target.symlink(request_info(req), parent_path.as_path(), name, link);
+        self.shared.reply_entry(parent_path.join(name), result, reply);
+    }
+
+    fn rename(&mut self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, reply: ReplyEmpty) {
+        let parent_path = get_path!(self.shared, parent, reply);
+        let newparent_path = get_path!(self.shared, newparent, reply);
+        let result = self.shared.target.rename(request_info(req), parent_path.as_path(), name,
+                                               newparent_path.as_path(), newname);
+        if result.is_ok() {
+            // the kernel keeps using the inode it knows the entry by
+            let old_path = parent_path.join(name);
+            let mut table = self.shared.inodes.lock().unwrap();
+            let InodeTable { ref mut inodes, ref mut keys } = *table;
+            for (&ino, inode) in inodes.iter_mut().filter(|(_, inode)| inode.path.starts_with(old_path.as_path())) {
+                let suffix = inode.path.strip_prefix(old_path.as_path()).unwrap().to_owned();
+                inode.path = newparent_path.join(newname).join(suffix);
+                let (_, key) = self.shared.target.inode(inode.path.as_path());
+                if keys.get(&inode.key) == Some(&ino) {
+                    keys.remove(&inode.key);
+                }
+                keys.entry(key.clone()).or_insert(ino);
+                inode.key = key;
+            }
+        }
+        reply_empty(result, reply);
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        let path = get_path!(self.shared, ino, reply);
+        match self.shared.target.open(request_info(req), path.as_path(), flags) {
+            Ok((fh, flags)) => reply.opened(fh, flags),
+            Err(error) => reply.error(error),
+        }
+    }
+
+    fn read(&mut self, _req: &Request, _ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        if offset < 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        self.spawn(move |shared| {
+            match shared.target.read_data(fh, offset as u64, size) {
+                Ok(data) => reply.data(&data),
+                Err(error) => reply.error(error),
+            }
+        });
+    }
+
+    fn write(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32, reply: ReplyWrite) {
+        let path = get_path!(self.shared, ino, reply);
+        if offset < 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        match self.shared.target.write(request_info(req), path.as_path(), fh, offset as u64, data.to_vec(), flags) {
+            Ok(written) => reply.written(written),
+            Err(error) => reply.error(error),
+        }
+    }
+
+    fn flush(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        let path = get_path!(self.shared, ino, reply);
+        reply_empty(self.shared.target.flush(request_info(req), path.as_path(), fh, lock_owner), reply);
+    }
+
+    fn release(&mut self, req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool, reply: ReplyEmpty) {
+        let path = get_path!(self.shared, ino, reply);
+        let result = self.shared.target.release(request_info(req), path.as_path(), fh, flags, lock_owner, flush);
+        reply_empty(result, reply);
+    }
+
+    fn fsync(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let path = get_path!(self.shared, ino, reply);
+        reply_empty(self.shared.target.fsync(request_info(req), path.as_path(), fh, datasync), reply);
+    }
+
+    fn opendir(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        let info = request_info(req);
+        self.spawn(move |shared| {
+            let path = get_path!(shared, ino, reply);
+            let result = shared.target.opendir(info, path.as_path(), flags)
+                .and_then(|(fh, flags)| Ok((shared.target.readdir(info, path.as_path(), fh)?, flags)));
+            match result {
+                Ok((entries, flags)) => {
+                    // the listing is kept until closed, so offsets stay meaningful
+                    let listing = shared.next_listing.fetch_add(1, Ordering::SeqCst);
+                    shared.listings.lock().unwrap().insert(listing, Arc::new(entries));
+                    reply.opened(listing, flags);
+                }
+                Err(error) => reply.error(error),
+            }
+        });
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        self.spawn(move |shared| {
+            let path = get_path!(shared, ino, reply);
+            let entries = match shared.listings.lock().unwrap().get(&fh) {
+                Some(entries) => entries.clone(),
+                None => {
+                    reply.error(libc::EBADF);
+                    return;
+                }
+            };
+            for (index, entry) in entries.iter().enumerate().skip(offset.max(0) as usize) {
+                let (derived, key) = shared.target.inode(path.join(entry.name.as_os_str()).as_path());
+                let entry_ino = shared.inodes.lock().unwrap().number(derived, key.as_str());
+                if reply.add(entry_ino, index as i64 + 1, entry.kind, entry.name.as_os_str()) {
+                    break;
+                }
+            }
+            reply.ok();
+        });
+    }
+
+    fn releasedir(&mut self, req: &Request, ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
+        self.shared.listings.lock().unwrap().remove(&fh);
+        let path = get_path!(self.shared, ino, reply);
+        reply_empty(self.shared.target.releasedir(request_info(req), path.as_path(), fh, flags), reply);
+    }
+
+    fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
+        let path = get_path!(self.shared, ino, reply);
+        match self.shared.target.statfs(request_info(req), path.as_path()) {
+            Ok(statfs) => reply.statfs(statfs.blocks, statfs.bfree, statfs.bavail, statfs.files,
+                                       statfs.ffree, statfs.bsize, statfs.namelen, statfs.frsize),
+            Err(error) => reply.error(error),
+        }
+    }
+
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let info = request_info(req);
+        let name = name.to_owned();
+        self.spawn(move |shared| {
+            let path = get_path!(shared, ino, reply);
+            reply_xattr(shared.target.getxattr(info, path.as_path(), name.as_os_str(), size), reply);
+        });
+    }
+
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        let info = request_info(req);
+        self.spawn(move |shared| {
+            let path = get_path!(shared, ino, reply);
+            reply_xattr(shared.target.listxattr(info, path.as_path(), size), reply);
+        });
+    }
+
+    fn create(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32, reply: ReplyCreate) {
+        let parent_path = get_path!(self.shared, parent, reply);
+        match self.shared.target.create(request_info(req), parent_path.as_path(), name, mode, flags) {
+            Ok(created) => {
+                let (ino, generation) = self.shared.remember(parent_path.join(name));
+                reply.created(&created.ttl, &fuse_attr(created.attr, ino), generation, created.fh, created.flags);
+            }
+            Err(error) => reply.error(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::sync::Arc;
+
+    use tempfile::tempdir;
+
+    use quantumfs::models::ledger::LocalLedger;
+    use quantumfs::models::repository::{DEFAULT_NAME, Repository};
+    use quantumfs::models::transaction::Transaction;
+    use quantumfs::operations::store::MemoryStore;
+
+    use crate::fs::QuantumFS;
+    use crate::inodes::{InodeFS, InodeTable};
+
+    fn dd(file: &Path, block_size: usize, skip: usize, count: usize) -> Vec<u8> {
+        let output = Command::new("dd")
+            .arg(format!("if={}", file.display()))
+            .arg(format!("bs={}", block_size))
+            .arg(format!("skip={}", skip))
+            .arg(format!("count={}", count))
+            .arg("status=none")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output.stdout
+    }
+
+    #[test]
+    fn colliding_keys_should_get_distinct_inodes() {
+        let mut table = InodeTable::new("root".to_string());
+        let (first, _) = table.remember(PathBuf::from("/first"), 10, "first".to_string());
+        let (second, _) = table.remember(PathBuf::from("/second"), 10, "second".to_string());
+        let (link, _) = table.remember(PathBuf::from("/link"), 10, "first".to_string());
+        assert_eq!((first, link), (10, 10));
+        assert_eq!(second, 11);
+        assert_eq!(table.number(10, "second"), 11);
+        assert_eq!(table.remember(PathBuf::from("/root"), 1, "other".to_string()), (2, 0));
+
+        table.forget(first, 2);
+        assert_eq!(table.number(10, "third"), 10);
+        assert_eq!(table.number(10, "second"), 11);
+    }
+
+    #[test]
+    fn renewed_inodes_should_report_a_new_generation() {
+        let mut table = InodeTable::new("root".to_string());
+        let (changed, _) = table.remember(PathBuf::from("/changed"), 10, "changed".to_string());
+        let (kept, _) = table.remember(PathBuf::from("/kept"), 20, "kept".to_string());
+        table.renew(&[changed, 30]);
+        assert_eq!(table.remember(PathBuf::from("/changed"), 10, "changed".to_string()), (changed, 1));
+        assert_eq!(table.remember(PathBuf::from("/kept"), 20, "kept".to_string()), (kept, 0));
+    }
+
+    #[test]
+    fn mounted_files_should_be_read_at_any_offset() {
+        let cache_dir = tempdir().unwrap();
+        let staging_dir = tempdir().unwrap();
+        let mountpoint = tempdir().unwrap();
+        let store = Arc::new(MemoryStore::new());
+        let ledger = LocalLedger::in_memory("owner").unwrap();
+        let mut repository = Repository::new(DEFAULT_NAME, Box::new(ledger), store.clone(), cache_dir.path());
+        let content: Vec<u8> = (0..5000u32).map(|index| (index % 251) as u8).collect();
+        let mut revision = repository.create_revision().unwrap();
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        fs::write(transaction.overlay_dir().join("file1"), &content).unwrap();
+        let tag = transaction.commit(store, 1000, 1024).unwrap();
+        repository.push_revision(&tag).unwrap();
+
+        let qfs = QuantumFS::new(repository).unwrap();
+        let options = ["-o", "ro"].iter().map(OsStr::new).collect::<Vec<&OsStr>>();
+        let session = unsafe { fuse::spawn_mount(InodeFS::new(qfs), &mountpoint.path(), &options) }
+            .unwrap();
+        let file = mountpoint.path().join("file1");
+        assert_eq!(fs::read(file.as_path()).unwrap(), content);
+        for &(block_size, skip, count) in [(7, 3, 5), (1000, 1, 2), (333, 14, 2), (4096, 1, 1), (13, 400, 1)].iter() {
+            let start = (block_size * skip).min(content.len());
+            let end = (block_size * (skip + count)).min(content.len());
+            assert_eq!(dd(file.as_path(), block_size, skip, count), &content[start..end]);
+        }
+        drop(session);
+    }
+}
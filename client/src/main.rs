@@ -1,11 +1,15 @@
 #[macro_use]
 extern crate clap;
 extern crate config;
+extern crate fuse;
 extern crate fuse_mt;
 #[macro_use]
 extern crate lazy_static;
+#[macro_use]
+extern crate log;
 extern crate libc;
 extern crate quantumfs;
+extern crate threadpool;
 extern crate time;
 extern crate web3;
 extern crate yaml_rust;
@@ -15,19 +19,42 @@ use crate::args::ARGS;
 
 mod commands;
 mod fs;
+mod inodes;
+mod overlay;
+mod ownership;
 mod settings;
 mod args;
 mod cache;
 
 fn main() {
-    if let Some(_) = ARGS.subcommand_matches("mount") {
-        commands::mount::mount();
+    if let Some(matches) = ARGS.subcommand_matches("mount") {
+        commands::mount::mount(matches.is_present("writable"));
     } else if let Some(_) = ARGS.subcommand_matches("transaction") {
         commands::transaction::transaction();
+    } else if let Some(_) = ARGS.subcommand_matches("abort") {
+        commands::abort::abort();
     } else if let Some(_) = ARGS.subcommand_matches("commit") {
         commands::commit::commit();
     } else if let Some(_) = ARGS.subcommand_matches("push") {
         commands::push::push();
+    } else if let Some(matches) = ARGS.subcommand_matches("cache") {
+        if let Some(_) = matches.subcommand_matches("stats") {
+            commands::cache::stats();
+        } else if let Some(_) = matches.subcommand_matches("clean") {
+            commands::cache::clean();
+        } else {
+            panic!("Invalid cache command");
+        }
+    } else if let Some(matches) = ARGS.subcommand_matches("acl") {
+        if let Some(matches) = matches.subcommand_matches("grant") {
+            commands::acl::grant(matches.value_of("publisher").unwrap());
+        } else if let Some(matches) = matches.subcommand_matches("revoke") {
+            commands::acl::revoke(matches.value_of("publisher").unwrap());
+        } else if let Some(_) = matches.subcommand_matches("list") {
+            commands::acl::list();
+        } else {
+            panic!("Invalid acl command");
+        }
     } else {
         panic!("Invalid command");
     }
@@ -0,0 +1,107 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use fuse_mt::FileType;
+
+use quantumfs::operations::overlay::{self, OPAQUE_MARKER};
+
+pub enum Layer {
+    Upper(PathBuf),
+    Lower,
+    Hidden,
+}
+
+pub struct Overlay {
+    upper_dir: PathBuf,
+}
+
+impl Overlay {
+    pub fn new(upper_dir: &Path) -> Self {
+        Self {
+            upper_dir: upper_dir.to_owned(),
+        }
+    }
+
+    pub fn upper_path(&self, path: &Path) -> PathBuf {
+        self.upper_dir.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    fn whiteout_path(&self, path: &Path) -> Option<PathBuf> {
+        let name = path.file_name()?;
+        Some(self.upper_path(path.parent()?).join(overlay::whiteout_name(name)))
+    }
+
+    /// Tells which layer serves `path`. Entries below a whiteout, an opaque
+    /// directory or a non-directory of the upper layer are hidden.
+    pub fn resolve(&self, path: &Path) -> Layer {
+        let mut current = self.upper_dir.clone();
+        let mut opaque = false;
+        for component in path.strip_prefix("/").unwrap_or(path).components() {
+            let name = component.as_os_str();
+            let upper = current.join(name);
+            if let Ok(metadata) = fs::symlink_metadata(upper.as_path()) {
+                opaque = opaque || !metadata.is_dir() || overlay::is_opaque(upper.as_path());
+                current = upper;
+                continue;
+            }
+            let whiteout = current.join(overlay::whiteout_name(name));
+            if opaque || fs::symlink_metadata(whiteout).is_ok() {
+                return Layer::Hidden;
+            }
+            return Layer::Lower;
+        }
+        Layer::Upper(current)
+    }
+
+    pub fn whiteout(&self, path: &Path) -> io::Result<()> {
+        match self.whiteout_path(path) {
+            Some(whiteout) => File::create(whiteout).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn remove_whiteout(&self, path: &Path) -> io::Result<bool> {
+        match self.whiteout_path(path) {
+            Some(ref whiteout) if fs::symlink_metadata(whiteout).is_ok() => {
+                fs::remove_file(whiteout)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub fn mark_opaque(&self, directory: &Path) -> io::Result<()> {
+        File::create(self.upper_path(directory).join(OPAQUE_MARKER)).map(|_| ())
+    }
+
+    /// Applies the upper directory on top of the entries listed by the lower one.
+    pub fn merge_listing(&self, directory: &Path, lower: BTreeMap<OsString, FileType>) -> io::Result<BTreeMap<OsString, FileType>> {
+        let mut entries = if overlay::is_opaque(directory) { BTreeMap::new() } else { lower };
+        let mut upper = Vec::new();
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name_str = name.to_string_lossy().into_owned();
+            if let Some(target) = overlay::whiteout_target(name_str.as_str()) {
+                entries.remove(&OsString::from(target));
+            } else if !overlay::is_special(name_str.as_str()) {
+                upper.push((name, get_file_type(&entry.file_type()?)));
+            }
+        }
+        entries.extend(upper);
+        Ok(entries)
+    }
+}
+
+pub fn get_file_type(file_type: &fs::FileType) -> FileType {
+    if file_type.is_dir() {
+        FileType::Directory
+    } else if file_type.is_symlink() {
+        FileType::Symlink
+    } else {
+        FileType::RegularFile
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use quantumfs::models::directoryentry::DirectoryEntry;
+
+/// Translation of the owners recorded in the catalogs into local ids.
+///
+/// Map files hold one rule per line, `uid <stored> <local>` or
+/// `gid <stored> <local>`, where `*` as the stored id matches every id
+/// without a rule of its own. Ids without any matching rule are preserved.
+/// Empty lines and lines starting with `#` are ignored.
+#[derive(Debug, Default)]
+pub struct OwnerMap {
+    uids: HashMap<i64, u32>,
+    gids: HashMap<i64, u32>,
+    default_uid: Option<u32>,
+    default_gid: Option<u32>,
+}
+
+impl OwnerMap {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let mut map = Self::default();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid rule at line {}: {}", number + 1, line),
+            );
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(invalid());
+            }
+            let local = fields[2].parse::<u32>().map_err(|_| invalid())?;
+            let (ids, default) = match fields[0] {
+                "uid" => (&mut map.uids, &mut map.default_uid),
+                "gid" => (&mut map.gids, &mut map.default_gid),
+                _ => return Err(invalid()),
+            };
+            match fields[1] {
+                "*" => *default = Some(local),
+                stored => {
+                    ids.insert(stored.parse::<i64>().map_err(|_| invalid())?, local);
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    fn translate(ids: &HashMap<i64, u32>, default: Option<u32>, id: i64) -> u32 {
+        ids.get(&id).cloned()
+            .or(default)
+            .unwrap_or(id as u32)
+    }
+}
+
+/// How the owners of the entries of a revision are reported to the kernel.
+/// Entries without a recorded owner are reported as owned by the mounting
+/// user in every mode.
+#[derive(Debug)]
+pub enum Ownership {
+    /// Reports the owners recorded when the entries were published.
+    Preserve,
+    /// Reports every entry as owned by the user mounting the file system.
+    MountingUser,
+    Map(OwnerMap),
+}
+
+impl Ownership {
+    pub fn uid(&self, dirent: &DirectoryEntry) -> u32 {
+        match (self, dirent.uid) {
+            (Ownership::Preserve, Some(uid)) => uid as u32,
+            (Ownership::Map(map), Some(uid)) => OwnerMap::translate(&map.uids, map.default_uid, uid),
+            (Ownership::MountingUser, _) | (_, None) => users::get_current_uid(),
+        }
+    }
+
+    pub fn gid(&self, dirent: &DirectoryEntry) -> u32 {
+        match (self, dirent.gid) {
+            (Ownership::Preserve, Some(gid)) => gid as u32,
+            (Ownership::Map(map), Some(gid)) => OwnerMap::translate(&map.gids, map.default_gid, gid),
+            (Ownership::MountingUser, _) | (_, None) => users::get_current_gid(),
+        }
+    }
+}
@@ -1,6 +1,8 @@
 
 use config::Config;
 
+use quantumfs::models::repository::DEFAULT_NAME;
+
 use crate::args::ARGS;
 use crate::cache::CACHE;
 
@@ -14,7 +16,15 @@ fn init() -> Config {
     config
         .set_default("web3", "http://127.0.0.1:8545").unwrap()
         .set_default("ipfs_gateway_server", "127.0.0.1").unwrap()
-        .set_default("ipfs_gateway_port", 5001).unwrap();
+        .set_default("ipfs_gateway_port", 5001).unwrap()
+        .set_default("catalog_max_entries", 200000).unwrap()
+        .set_default("chunk_size", 1048576).unwrap()
+        .set_default("cache_quota", 10737418240i64).unwrap()
+        .set_default("storage", "ipfs").unwrap()
+        .set_default("ledger", "ethereum").unwrap()
+        .set_default("ownership", "preserve").unwrap()
+        .set_default("follow_interval", 60).unwrap()
+        .set_default("repository", DEFAULT_NAME).unwrap();
     // Add the custom configuration file, if present
     if let Some(config_file) = ARGS.value_of("config") {
         config.merge(config::File::with_name(config_file))
@@ -34,10 +44,18 @@ fn init() -> Config {
     if let Some(address) = ARGS.value_of("address") {
         config.set("address", address).unwrap();
     }
+    // Add the owner of the file system if passed as a parameter
+    if let Some(owner) = ARGS.value_of("owner") {
+        config.set("owner", owner).unwrap();
+    }
     // Add the contract's ethereum address if passed as a parameter
     if let Some(address) = ARGS.value_of("contract") {
         config.set("contract", address).unwrap();
     }
+    // Add the repository name if passed as a parameter
+    if let Some(repository) = ARGS.value_of("repository") {
+        config.set("repository", repository).unwrap();
+    }
     // Add the mountpoint if passed as a parameter
     if let Some(mountpoint) = ARGS.value_of("mountpoint") {
         config.set("mountpoint", mountpoint).unwrap();
@@ -54,5 +72,33 @@ fn init() -> Config {
     if let Some(ipfs_port) = ARGS.value_of("ipfs-port") {
         config.set("ipfs-port", ipfs_port).unwrap();
     }
+    // Add the storage backend if passed as a parameter
+    if let Some(storage) = ARGS.value_of("storage") {
+        config.set("storage", storage).unwrap();
+    }
+    // Add the storage directory if passed as a parameter
+    if let Some(storage_dir) = ARGS.value_of("storage-dir") {
+        config.set("storage_dir", storage_dir).unwrap();
+    }
+    // Add the ledger backend if passed as a parameter
+    if let Some(ledger) = ARGS.value_of("ledger") {
+        config.set("ledger", ledger).unwrap();
+    }
+    // Add the ledger file if passed as a parameter
+    if let Some(ledger_file) = ARGS.value_of("ledger-file") {
+        config.set("ledger_file", ledger_file).unwrap();
+    }
+    // Add the ownership mode if passed as a parameter
+    if let Some(ownership) = ARGS.value_of("ownership") {
+        config.set("ownership", ownership).unwrap();
+    }
+    // Add the ownership map file if passed as a parameter
+    if let Some(ownership_map) = ARGS.value_of("ownership-map") {
+        config.set("ownership_map", ownership_map).unwrap();
+    }
+    // Add the interval to check for new revisions if passed as a parameter
+    if let Some(follow_interval) = ARGS.value_of("follow-interval") {
+        config.set("follow_interval", follow_interval).unwrap();
+    }
     config
 }
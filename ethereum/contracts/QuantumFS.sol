@@ -2,49 +2,101 @@ pragma solidity ^0.5.0;
 
 contract QuantumFS
 {
-    mapping(address => string[]) private fileSystems;
+    mapping(address => mapping(string => string[])) private fileSystems;
+    // accounts the owner lets add revisions to each file system, and their position in it (plus one)
+    mapping(address => mapping(string => address[])) private publishers;
+    mapping(address => mapping(string => mapping(address => uint))) private publisherIndexes;
 
-    function currentRevision()
+    event RevisionAdded(address indexed owner, string indexed name, uint indexed revision, string hash);
+    event Evicted(address indexed owner, string indexed name, uint revisions);
+    event PublisherGranted(address indexed owner, string indexed name, address indexed publisher);
+    event PublisherRevoked(address indexed owner, string indexed name, address indexed publisher);
+
+    function currentRevision(address _owner, string memory _name)
       public
       view
       returns (string memory, uint)
     {
-        uint totalRevisions = totalRevisions();
+        uint totalRevisions = totalRevisions(_owner, _name);
         if (totalRevisions == 0) {
             return ("0000000000000000000000000000000000000000000000", 0);
         }
 
-        uint lastRevision = totalRevisions - 1;
-        return getRevision(lastRevision);
+        return getRevision(_owner, _name, totalRevisions);
     }
 
-    function totalRevisions()
+    function totalRevisions(address _owner, string memory _name)
       public
       view
       returns (uint)
     {
-        return fileSystems[msg.sender].length;
+        return fileSystems[_owner][_name].length;
     }
 
-    function getRevision(uint _revision)
+    function getRevision(address _owner, string memory _name, uint _revision)
       public
       view
       returns (string memory, uint)
     {
         require(_revision > 0, "Revision number must be greater than zero");
-        require(_revision <= totalRevisions(), "Invalid revision");
-        return (fileSystems[msg.sender][_revision - 1], _revision);
+        require(_revision <= totalRevisions(_owner, _name), "Invalid revision");
+        return (fileSystems[_owner][_name][_revision - 1], _revision);
+    }
+
+    function isPublisher(address _owner, string memory _name, address _account)
+      public
+      view
+      returns (bool)
+    {
+        return _account == _owner || publisherIndexes[_owner][_name][_account] > 0;
+    }
+
+    function getPublishers(address _owner, string memory _name)
+      public
+      view
+      returns (address[] memory)
+    {
+        return publishers[_owner][_name];
+    }
+
+    function grantPublisher(string calldata _name, address _publisher)
+      external
+    {
+        require(!isPublisher(msg.sender, _name, _publisher), "Already a publisher");
+        publishers[msg.sender][_name].push(_publisher);
+        publisherIndexes[msg.sender][_name][_publisher] = publishers[msg.sender][_name].length;
+        emit PublisherGranted(msg.sender, _name, _publisher);
+    }
+
+    function revokePublisher(string calldata _name, address _publisher)
+      external
+    {
+        uint index = publisherIndexes[msg.sender][_name][_publisher];
+        require(index > 0, "Not a publisher");
+        address[] storage list = publishers[msg.sender][_name];
+        address last = list[list.length - 1];
+        list[index - 1] = last;
+        publisherIndexes[msg.sender][_name][last] = index;
+        list.length--;
+        delete publisherIndexes[msg.sender][_name][_publisher];
+        emit PublisherRevoked(msg.sender, _name, _publisher);
     }
 
-    function addRevision(string calldata _hash)
+    // _base is the revision the new one was made from, so concurrent publishers cannot overwrite each other
+    function addRevision(address _owner, string calldata _name, uint _base, string calldata _hash)
       external
     {
-        fileSystems[msg.sender].push(_hash);
+        require(isPublisher(_owner, _name, msg.sender), "Not a publisher of the file system");
+        require(fileSystems[_owner][_name].length == _base, "The file system moved past the base revision");
+        fileSystems[_owner][_name].push(_hash);
+        emit RevisionAdded(_owner, _name, fileSystems[_owner][_name].length, _hash);
     }
 
-    function evict()
+    function evict(string calldata _name)
       external
     {
-        delete fileSystems[msg.sender];
+        uint revisions = fileSystems[msg.sender][_name].length;
+        delete fileSystems[msg.sender][_name];
+        emit Evicted(msg.sender, _name, revisions);
     }
 }
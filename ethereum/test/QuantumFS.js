@@ -1,7 +1,128 @@
 const QuantumFS = artifacts.require("QuantumFS");
 const truffleAssert = require('truffle-assertions');
 
+const GENESIS = "0000000000000000000000000000000000000000000000";
+const HASH_1 = "QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4";
+const HASH_2 = "QmWE6s8qazNrzGEHLfVA5PAFieT1nsoqU11pggfoWwSis5";
+const NAME = "releases";
+
 
 contract("QuantumFS", accounts => {
+    let instance;
+
+    beforeEach(async () => {
+        instance = await QuantumFS.new();
+    });
+
+    it("should start at the genesis revision", async () => {
+        const result = await instance.currentRevision(accounts[0], NAME);
+        assert.equal(result[0], GENESIS);
+        assert.equal(result[1].toNumber(), 0);
+    });
+
+    it("should return the last pushed revision", async () => {
+        await instance.addRevision(accounts[0], NAME, 0, HASH_1, {from: accounts[0]});
+        await instance.addRevision(accounts[0], NAME, 1, HASH_2, {from: accounts[0]});
+        const result = await instance.currentRevision(accounts[0], NAME);
+        assert.equal(result[0], HASH_2);
+        assert.equal(result[1].toNumber(), 2);
+        const first = await instance.getRevision(accounts[0], NAME, 1);
+        assert.equal(first[0], HASH_1);
+    });
+
+    it("should keep the file systems of each account apart", async () => {
+        await instance.addRevision(accounts[0], NAME, 0, HASH_1, {from: accounts[0]});
+        const result = await instance.currentRevision(accounts[1], NAME);
+        assert.equal(result[1].toNumber(), 0);
+    });
+
+    it("should keep the file systems of each name apart", async () => {
+        await instance.addRevision(accounts[0], NAME, 0, HASH_1, {from: accounts[0]});
+        await instance.addRevision(accounts[0], "datasets", 0, HASH_2, {from: accounts[0]});
+        const releases = await instance.currentRevision(accounts[0], NAME);
+        assert.equal(releases[0], HASH_1);
+        assert.equal(releases[1].toNumber(), 1);
+        const datasets = await instance.currentRevision(accounts[0], "datasets");
+        assert.equal(datasets[0], HASH_2);
+        assert.equal(datasets[1].toNumber(), 1);
+    });
+
+    it("should let anyone read a file system", async () => {
+        await instance.addRevision(accounts[0], NAME, 0, HASH_1, {from: accounts[0]});
+        const result = await instance.currentRevision(accounts[0], NAME, {from: accounts[1]});
+        assert.equal(result[0], HASH_1);
+        assert.equal(result[1].toNumber(), 1);
+    });
+
+    it("should reject out of range revisions", async () => {
+        await truffleAssert.reverts(instance.getRevision(accounts[0], NAME, 1));
+    });
+
+    it("should reject revisions made from an older base", async () => {
+        await instance.addRevision(accounts[0], NAME, 0, HASH_1, {from: accounts[0]});
+        await truffleAssert.reverts(instance.addRevision(accounts[0], NAME, 0, HASH_2, {from: accounts[0]}));
+        const result = await instance.currentRevision(accounts[0], NAME);
+        assert.equal(result[0], HASH_1);
+        assert.equal(result[1].toNumber(), 1);
+    });
+
+    it("should announce every added revision", async () => {
+        await instance.addRevision(accounts[0], NAME, 0, HASH_1, {from: accounts[0]});
+        const result = await instance.addRevision(accounts[0], NAME, 1, HASH_2, {from: accounts[0]});
+        truffleAssert.eventEmitted(result, "RevisionAdded", event =>
+            event.owner === accounts[0] && event.revision.toNumber() === 2 && event.hash === HASH_2);
+    });
+
+    it("should announce evictions", async () => {
+        await instance.addRevision(accounts[0], NAME, 0, HASH_1, {from: accounts[0]});
+        const result = await instance.evict(NAME, {from: accounts[0]});
+        truffleAssert.eventEmitted(result, "Evicted", event =>
+            event.owner === accounts[0] && event.revisions.toNumber() === 1);
+    });
+
+    it("should only let publishers add revisions", async () => {
+        await truffleAssert.reverts(instance.addRevision(accounts[0], NAME, 0, HASH_1, {from: accounts[1]}));
+        await instance.grantPublisher(NAME, accounts[1], {from: accounts[0]});
+        await instance.addRevision(accounts[0], NAME, 0, HASH_1, {from: accounts[1]});
+        const result = await instance.currentRevision(accounts[0], NAME);
+        assert.equal(result[0], HASH_1);
+        assert.equal(result[1].toNumber(), 1);
+        const other = await instance.currentRevision(accounts[1], NAME);
+        assert.equal(other[1].toNumber(), 0);
+    });
+
+    it("should keep the publishers of each name apart", async () => {
+        await instance.grantPublisher(NAME, accounts[1], {from: accounts[0]});
+        await truffleAssert.reverts(instance.addRevision(accounts[0], "datasets", 0, HASH_1, {from: accounts[1]}));
+    });
+
+    it("should list granted publishers until they are revoked", async () => {
+        await instance.grantPublisher(NAME, accounts[1], {from: accounts[0]});
+        await instance.grantPublisher(NAME, accounts[2], {from: accounts[0]});
+        await truffleAssert.reverts(instance.grantPublisher(NAME, accounts[1], {from: accounts[0]}));
+        assert.deepEqual(await instance.getPublishers(accounts[0], NAME), [accounts[1], accounts[2]]);
+        await instance.revokePublisher(NAME, accounts[1], {from: accounts[0]});
+        assert.deepEqual(await instance.getPublishers(accounts[0], NAME), [accounts[2]]);
+        assert.isFalse(await instance.isPublisher(accounts[0], NAME, accounts[1]));
+        assert.isTrue(await instance.isPublisher(accounts[0], NAME, accounts[2]));
+        await truffleAssert.reverts(instance.addRevision(accounts[0], NAME, 0, HASH_1, {from: accounts[1]}));
+        await truffleAssert.reverts(instance.revokePublisher(NAME, accounts[1], {from: accounts[0]}));
+    });
+
+    it("should announce granted and revoked publishers", async () => {
+        const granted = await instance.grantPublisher(NAME, accounts[1], {from: accounts[0]});
+        truffleAssert.eventEmitted(granted, "PublisherGranted", event =>
+            event.owner === accounts[0] && event.publisher === accounts[1]);
+        const revoked = await instance.revokePublisher(NAME, accounts[1], {from: accounts[0]});
+        truffleAssert.eventEmitted(revoked, "PublisherRevoked", event =>
+            event.owner === accounts[0] && event.publisher === accounts[1]);
+    });
 
+    it("should not let publishers evict the file system", async () => {
+        await instance.grantPublisher(NAME, accounts[1], {from: accounts[0]});
+        await instance.addRevision(accounts[0], NAME, 0, HASH_1, {from: accounts[1]});
+        await instance.evict(NAME, {from: accounts[1]});
+        const result = await instance.currentRevision(accounts[0], NAME);
+        assert.equal(result[1].toNumber(), 1);
+    });
 });
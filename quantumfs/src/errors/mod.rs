@@ -2,61 +2,160 @@ use std::error::Error as StdError;
 use std::fmt::{Display, Formatter, Result};
 use std::io::Error as IOError;
 
+use ethabi::Error as AbiError;
 use failure::Error as FailureError;
+use reqwest::Error as ReqwestError;
 use serde_json::Error as SerdeError;
 use rusqlite::Error as SqliteError;
 use web3::contract::Error as ContractError;
+use web3::Error as Web3Error;
+
+use crate::types::ipfs::IpfsHash;
+
+pub type ErrorSource = Box<dyn StdError + Send + 'static>;
 
 #[derive(Debug)]
-pub struct QFSError {
-    details: String
+pub enum QFSError {
+    /// There is no entry at the given path.
+    NotFound(String),
+    /// A directory was expected at the given path, but something else is there.
+    NotADirectory(String),
+    /// A regular file was expected at the given path, but something else,
+    /// such as a symlink, is there.
+    NotAFile(String),
+    /// A file was expected at the given path, but a directory is there.
+    IsADirectory(String),
+    /// The string is not a valid IPFS hash.
+    InvalidHash(String),
+    /// The object store could not be reached or failed to serve an object.
+    Storage(String, Option<ErrorSource>),
+    /// The revision ledger could not be reached or rejected a request.
+    Ledger(String, Option<ErrorSource>),
+    /// A catalog or cache database query failed.
+    Database(SqliteError),
+    /// The catalog was written with a schema revision newer than supported.
+    UnsupportedSchema(i64),
+    /// The bytes of an object do not hash to the hash it was requested by.
+    Integrity { expected: IpfsHash, actual: String },
+    /// The ledger moved on from the revision a commit was based on.
+    Conflict { base: u128, current: u128 },
+    /// A local file operation failed.
+    Io(IOError),
+    /// Any other failure, described by its message.
+    Other(String),
 }
 
 impl Display for QFSError {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        write!(f, "QuantumFS Error: \"{}\"", &self.details)
+        match self {
+            QFSError::NotFound(path) => write!(f, "QuantumFS Error: \"{} not found\"", path),
+            QFSError::NotADirectory(path) => write!(f, "QuantumFS Error: \"{} is not a directory\"", path),
+            QFSError::NotAFile(path) => write!(f, "QuantumFS Error: \"{} is not a file\"", path),
+            QFSError::IsADirectory(path) => write!(f, "QuantumFS Error: \"{} is a directory\"", path),
+            QFSError::InvalidHash(hash) => write!(f, "QuantumFS Error: \"Invalid IPFS hash {}\"", hash),
+            QFSError::Storage(details, _) => write!(f, "QuantumFS Error: \"Storage: {}\"", details),
+            QFSError::Ledger(details, _) => write!(f, "QuantumFS Error: \"Ledger: {}\"", details),
+            QFSError::Database(error) => write!(f, "QuantumFS Error: \"Database: {}\"", error),
+            QFSError::UnsupportedSchema(revision) =>
+                write!(f, "QuantumFS Error: \"Unsupported catalog schema revision {}\"", revision),
+            QFSError::Integrity { expected, actual } =>
+                write!(f, "QuantumFS Error: \"Object {} has hash {}\"", expected, actual),
+            QFSError::Conflict { base, current } =>
+                write!(f, "QuantumFS Error: \"Commit based on revision {} but the ledger is at revision {}\"", base, current),
+            QFSError::Io(error) => write!(f, "QuantumFS Error: \"{}\"", error),
+            QFSError::Other(details) => write!(f, "QuantumFS Error: \"{}\"", details),
+        }
     }
 }
 
 impl StdError for QFSError {
     fn source(&self) -> Option<&(dyn StdError + 'static)> {
-        None
+        match self {
+            QFSError::Storage(_, Some(source)) | QFSError::Ledger(_, Some(source)) =>
+                Some(source.as_ref() as &(dyn StdError + 'static)),
+            QFSError::Database(error) => Some(error),
+            QFSError::Io(error) => Some(error),
+            _ => None,
+        }
     }
 }
 
 impl From<SqliteError> for QFSError {
     fn from(err: SqliteError) -> Self {
-        QFSError { details: format!("{}", err) }
+        QFSError::Database(err)
     }
 }
 
 impl From<FailureError> for QFSError {
     fn from(err: FailureError) -> Self {
-        QFSError { details: format!("{}", err) }
+        QFSError::Storage(format!("{}", err), Some(Box::new(err.compat())))
+    }
+}
+
+impl From<ReqwestError> for QFSError {
+    fn from(err: ReqwestError) -> Self {
+        QFSError::Storage(format!("{}", err), Some(Box::new(err)))
     }
 }
 
 impl From<ContractError> for QFSError {
     fn from(err: ContractError) -> Self {
-        QFSError { details: format!("{}", err) }
+        QFSError::Ledger(format!("{}", err), Some(Box::new(err)))
+    }
+}
+
+impl From<AbiError> for QFSError {
+    fn from(err: AbiError) -> Self {
+        QFSError::Ledger(format!("{}", err), Some(Box::new(err)))
+    }
+}
+
+impl From<Web3Error> for QFSError {
+    fn from(err: Web3Error) -> Self {
+        QFSError::Ledger(format!("{}", err), Some(Box::new(err)))
     }
 }
 
 impl From<IOError> for QFSError {
     fn from(err: IOError) -> Self {
-        QFSError { details: format!("{}", err) }
+        QFSError::Io(err)
     }
 }
 
 impl From<SerdeError> for QFSError {
     fn from(err: SerdeError) -> Self {
-        QFSError { details: format!("{}", err) }
+        QFSError::Ledger(format!("{}", err), Some(Box::new(err)))
     }
 }
 
 impl QFSError {
     pub fn new(details: &str) -> Self {
-        Self { details: String::from(details) }
+        QFSError::Other(String::from(details))
+    }
+
+    pub fn storage(details: &str) -> Self {
+        QFSError::Storage(String::from(details), None)
+    }
+
+    pub fn ledger(details: &str) -> Self {
+        QFSError::Ledger(String::from(details), None)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::io;
+
+    use crate::errors::QFSError;
+
+    #[test]
+    fn source_should_chain_the_underlying_error() {
+        let error = QFSError::from(io::Error::other("disk on fire"));
+        assert_eq!(error.source().unwrap().to_string(), "disk on fire");
+        assert!(QFSError::NotFound(String::from("/file1")).source().is_none());
+        assert_eq!(QFSError::NotFound(String::from("/file1")).to_string(),
+                   "QuantumFS Error: \"/file1 not found\"");
+    }
+}
@@ -1,14 +1,18 @@
 extern crate base58;
+extern crate ethabi;
 extern crate filepath;
 extern crate ipfsapi;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 extern crate multihash;
 extern crate path_absolutize;
 extern crate regex;
+extern crate reqwest;
 extern crate rusqlite;
 extern crate serde_json;
 extern crate tempfile;
+extern crate tiny_keccak;
 extern crate web3;
 
 pub mod operations;
@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{Connection, NO_PARAMS, OpenFlags, ToSql};
+
+use crate::errors::QFSError;
+use crate::operations::ipfs;
+use crate::types::ipfs::IpfsHash;
+
+/// Accesses to an object closer in time than this are recorded only once.
+static TOUCH_INTERVAL: i64 = 60 * 1000;
+
+lazy_static! {
+    static ref CREATE_TABLES: String = String::from(
+    "CREATE TABLE IF NOT EXISTS objects (hash TEXT, size INTEGER, atime INTEGER, \
+        CONSTRAINT pk_objects PRIMARY KEY (hash)); \
+    CREATE TABLE IF NOT EXISTS pins (hash TEXT, pid INTEGER, \
+        CONSTRAINT pk_pins PRIMARY KEY (hash, pid));"
+    );
+
+    static ref LIST_OBJECTS: String = String::from(
+    "SELECT hash \
+        FROM objects;"
+    );
+
+    static ref REPLACE_OBJECT: String = String::from(
+    "INSERT OR REPLACE INTO objects (hash, size, atime) \
+        VALUES (?, ?, ?);"
+    );
+
+    static ref DELETE_OBJECT: String = String::from(
+    "DELETE FROM objects \
+        WHERE hash = ?;"
+    );
+
+    static ref OBJECT_STATS: String = String::from(
+    "SELECT COUNT(*), IFNULL(SUM(size), 0) \
+        FROM objects;"
+    );
+
+    static ref LIST_EVICTABLE: String = String::from(
+    "SELECT hash, size \
+        FROM objects \
+        WHERE hash NOT IN (SELECT hash FROM pins) \
+        ORDER BY atime ASC;"
+    );
+
+    static ref LIST_PIDS: String = String::from(
+    "SELECT DISTINCT pid \
+        FROM pins;"
+    );
+
+    static ref COUNT_PINNED: String = String::from(
+    "SELECT COUNT(DISTINCT hash) \
+        FROM pins;"
+    );
+
+    static ref INSERT_PIN: String = String::from(
+    "INSERT OR IGNORE INTO pins (hash, pid) \
+        VALUES (?, ?);"
+    );
+
+    static ref DELETE_PIN: String = String::from(
+    "DELETE FROM pins \
+        WHERE hash = ? AND pid = ?;"
+    );
+
+    static ref DELETE_PINS: String = String::from(
+    "DELETE FROM pins \
+        WHERE pid = ?;"
+    );
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+}
+
+fn is_alive(pid: i64) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct CacheStats {
+    pub objects: i64,
+    pub size: i64,
+    pub pinned: i64,
+    pub quota: u64,
+}
+
+/// Keeps the objects cached in a directory under a quota, evicting the least
+/// recently used ones. Objects pinned by a running process, like the catalogs
+/// of a mounted revision or its open files, are never evicted. The
+/// bookkeeping lives in a sqlite database shared by every process using the
+/// same cache.
+#[derive(Debug)]
+pub struct CacheManager {
+    directory: PathBuf,
+    quota: u64,
+    connection: Mutex<Connection>,
+    touched: Mutex<HashMap<IpfsHash, i64>>,
+    pins: Mutex<HashMap<IpfsHash, usize>>,
+}
+
+impl CacheManager {
+    pub fn open(directory: &Path, database: &Path, quota: u64) -> Result<Self, QFSError> {
+        let connection = Connection::open_with_flags(database, OpenFlags::default())?;
+        connection.execute_batch(CREATE_TABLES.as_str())?;
+        let manager = Self {
+            directory: directory.to_owned(),
+            quota,
+            connection: Mutex::new(connection),
+            touched: Mutex::new(HashMap::new()),
+            pins: Mutex::new(HashMap::new()),
+        };
+        manager.scan()?;
+        Ok(manager)
+    }
+
+    /// Brings the bookkeeping in line with the objects actually present in
+    /// the cache directory.
+    fn scan(&self) -> Result<(), QFSError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(LIST_OBJECTS.as_str())?;
+        let mut rows = statement.query(NO_PARAMS)?;
+        let mut known = Vec::new();
+        while let Some(row) = rows.next()? {
+            known.push(row.get::<_, String>(0)?);
+        }
+        for hash in known.iter() {
+            if !self.directory.join(hash).is_file() {
+                connection.execute(DELETE_OBJECT.as_str(), &[hash])?;
+            }
+        }
+        for entry in fs::read_dir(self.directory.as_path())? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() || !ipfs::validate_ipfs_hash(name.as_str()) || known.contains(&name) {
+                continue;
+            }
+            let atime = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+            connection.execute(
+                REPLACE_OBJECT.as_str(),
+                &[&name as &dyn ToSql, &(metadata.len() as i64), &atime],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn record_access(&self, hash: &IpfsHash, size: u64, time: i64) -> Result<(), QFSError> {
+        self.connection.lock().unwrap().execute(
+            REPLACE_OBJECT.as_str(),
+            &[&hash.to_string() as &dyn ToSql, &(size as i64), &time],
+        )?;
+        self.touched.lock().unwrap().insert(hash.clone(), time);
+        Ok(())
+    }
+
+    /// Records an access to a cached object.
+    pub fn touch(&self, hash: &IpfsHash, size: u64) -> Result<(), QFSError> {
+        let time = now();
+        match self.touched.lock().unwrap().get(hash) {
+            Some(last) if time - last < TOUCH_INTERVAL => return Ok(()),
+            _ => {}
+        }
+        self.record_access(hash, size, time)
+    }
+
+    /// Records a newly cached object, evicting others if the quota is exceeded.
+    /// The new object itself is kept, since its caller is about to use it.
+    pub fn add(&self, hash: &IpfsHash, size: u64) -> Result<(), QFSError> {
+        self.record_access(hash, size, now())?;
+        self.evict_except(self.quota, Some(hash))?;
+        Ok(())
+    }
+
+    pub fn pin(&self, hash: &IpfsHash) -> Result<(), QFSError> {
+        let mut pins = self.pins.lock().unwrap();
+        let count = pins.entry(hash.clone()).or_insert(0);
+        *count += 1;
+        if *count == 1 {
+            self.connection.lock().unwrap().execute(
+                INSERT_PIN.as_str(),
+                &[&hash.to_string() as &dyn ToSql, &(process::id() as i64)],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn unpin(&self, hash: &IpfsHash) -> Result<(), QFSError> {
+        let mut pins = self.pins.lock().unwrap();
+        let count = match pins.get_mut(hash) {
+            Some(count) => count,
+            None => return Ok(()),
+        };
+        *count -= 1;
+        if *count == 0 {
+            pins.remove(hash);
+            self.connection.lock().unwrap().execute(
+                DELETE_PIN.as_str(),
+                &[&hash.to_string() as &dyn ToSql, &(process::id() as i64)],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Drops the pins left behind by processes that are no longer running.
+    fn release_stale_pins(&self, connection: &Connection) -> Result<(), QFSError> {
+        let mut statement = connection.prepare(LIST_PIDS.as_str())?;
+        let mut rows = statement.query(NO_PARAMS)?;
+        let mut pids = Vec::new();
+        while let Some(row) = rows.next()? {
+            pids.push(row.get::<_, i64>(0)?);
+        }
+        for pid in pids.into_iter().filter(|pid| !is_alive(*pid)) {
+            connection.execute(DELETE_PINS.as_str(), [pid])?;
+        }
+        Ok(())
+    }
+
+    /// Evicts the least recently used objects until the cache takes at most
+    /// `limit` bytes, or only pinned objects are left. Returns the number of
+    /// objects and bytes evicted.
+    pub fn evict(&self, limit: u64) -> Result<(i64, i64), QFSError> {
+        self.evict_except(limit, None)
+    }
+
+    fn evict_except(&self, limit: u64, kept: Option<&IpfsHash>) -> Result<(i64, i64), QFSError> {
+        let connection = self.connection.lock().unwrap();
+        self.release_stale_pins(&connection)?;
+        let (_, mut size): (i64, i64) = connection.query_row(
+            OBJECT_STATS.as_str(), NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let (mut evicted_objects, mut evicted_size) = (0, 0);
+        if size as u64 <= limit {
+            return Ok((evicted_objects, evicted_size));
+        }
+        let mut statement = connection.prepare(LIST_EVICTABLE.as_str())?;
+        let mut rows = statement.query(NO_PARAMS)?;
+        let mut candidates = Vec::new();
+        while let Some(row) = rows.next()? {
+            candidates.push((row.get::<_, String>(0)?, row.get::<_, i64>(1)?));
+        }
+        let kept = kept.map(|hash| hash.to_string());
+        for (hash, object_size) in candidates {
+            if size as u64 <= limit {
+                break;
+            }
+            if Some(&hash) == kept.as_ref() {
+                continue;
+            }
+            match fs::remove_file(self.directory.join(hash.as_str())) {
+                Err(ref error) if error.kind() == ErrorKind::NotFound => {}
+                result => result?,
+            }
+            connection.execute(DELETE_OBJECT.as_str(), &[&hash])?;
+            size -= object_size;
+            evicted_objects += 1;
+            evicted_size += object_size;
+        }
+        Ok((evicted_objects, evicted_size))
+    }
+
+    /// Evicts every object that is not pinned.
+    pub fn clean(&self) -> Result<(i64, i64), QFSError> {
+        self.evict(0)
+    }
+
+    pub fn stats(&self) -> Result<CacheStats, QFSError> {
+        let connection = self.connection.lock().unwrap();
+        self.release_stale_pins(&connection)?;
+        let (objects, size) = connection.query_row(
+            OBJECT_STATS.as_str(), NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let pinned = connection.query_row(COUNT_PINNED.as_str(), NO_PARAMS, |row| row.get(0))?;
+        Ok(CacheStats {
+            objects,
+            size,
+            pinned,
+            quota: self.quota,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::{tempdir, TempDir};
+
+    use crate::models::cache::CacheManager;
+    use crate::operations::ipfs;
+    use crate::types::ipfs::IpfsHash;
+
+    fn cache_object(directory: &TempDir, content: &str) -> IpfsHash {
+        let hash = IpfsHash::new(ipfs::hash_bytes(content.as_bytes()).as_str()).unwrap();
+        fs::write(directory.path().join(hash.as_ref()), content).unwrap();
+        hash
+    }
+
+    #[test]
+    fn evict_should_drop_the_least_recently_used_objects() {
+        let directory = tempdir().unwrap();
+        let database = tempdir().unwrap();
+        let cache = CacheManager::open(directory.path(), database.path().join("cache").as_path(), 100).unwrap();
+        let first = cache_object(&directory, "first");
+        let second = cache_object(&directory, "second");
+        let third = cache_object(&directory, "third");
+        cache.record_access(&second, 6, 1).unwrap();
+        cache.record_access(&first, 5, 2).unwrap();
+        cache.record_access(&third, 5, 3).unwrap();
+
+        assert_eq!(cache.evict(11).unwrap(), (1, 6));
+        assert!(!directory.path().join(second.as_ref()).exists());
+        assert!(directory.path().join(first.as_ref()).exists());
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.objects, stats.size), (2, 10));
+    }
+
+    #[test]
+    fn evict_should_keep_pinned_objects() {
+        let directory = tempdir().unwrap();
+        let database = tempdir().unwrap();
+        let cache = CacheManager::open(directory.path(), database.path().join("cache").as_path(), 100).unwrap();
+        let first = cache_object(&directory, "first");
+        let second = cache_object(&directory, "second");
+        cache.record_access(&first, 5, 1).unwrap();
+        cache.record_access(&second, 6, 2).unwrap();
+        cache.pin(&first).unwrap();
+        cache.pin(&first).unwrap();
+        cache.unpin(&first).unwrap();
+
+        assert_eq!(cache.clean().unwrap(), (1, 6));
+        assert!(directory.path().join(first.as_ref()).exists());
+        assert_eq!(cache.stats().unwrap().pinned, 1);
+        cache.unpin(&first).unwrap();
+        assert_eq!(cache.clean().unwrap(), (1, 5));
+    }
+
+    #[test]
+    fn add_should_keep_the_new_object() {
+        let directory = tempdir().unwrap();
+        let database = tempdir().unwrap();
+        let cache = CacheManager::open(directory.path(), database.path().join("cache").as_path(), 5).unwrap();
+        let first = cache_object(&directory, "first");
+        let second = cache_object(&directory, "second");
+        cache.record_access(&first, 5, 1).unwrap();
+        cache.add(&second, 6).unwrap();
+
+        assert!(!directory.path().join(first.as_ref()).exists());
+        assert!(directory.path().join(second.as_ref()).exists());
+    }
+
+    #[test]
+    fn open_should_restore_the_bookkeeping() {
+        let directory = tempdir().unwrap();
+        let database = tempdir().unwrap();
+        let database_path = database.path().join("cache");
+        let first = cache_object(&directory, "first");
+        fs::create_dir(directory.path().join("quarantine")).unwrap();
+        {
+            let cache = CacheManager::open(directory.path(), database_path.as_path(), 100).unwrap();
+            assert_eq!(cache.stats().unwrap().objects, 1);
+            cache_object(&directory, "second");
+            fs::remove_file(directory.path().join(first.as_ref())).unwrap();
+        }
+        let cache = CacheManager::open(directory.path(), database_path.as_path(), 100).unwrap();
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.objects, stats.size), (1, 6));
+    }
+}
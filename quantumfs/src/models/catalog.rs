@@ -1,37 +1,40 @@
 use std::{fmt, fs};
 use std::fs::File;
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use rusqlite::{Connection, OpenFlags};
-use tempfile::NamedTempFile;
+use tempfile::{NamedTempFile, TempPath};
 
 use crate::errors::QFSError;
+use crate::models::chunk::Chunk;
 use crate::models::directoryentry::{DirectoryEntry, flags};
 use crate::operations::{database, ipfs};
 use crate::operations::path;
+use crate::operations::xattr::Xattrs;
 use crate::types::ipfs::IpfsHash;
 use filepath::FilePath;
 
 #[derive(Debug, Clone)]
 pub struct CatalogReference {
-    path: IpfsHash,
+    path: String,
     hash: IpfsHash,
     size: i64,
 }
 
 impl CatalogReference {
-    pub fn new(path: &IpfsHash, hash: &IpfsHash, size: i64) -> Self {
+    pub fn new(path: &str, hash: &IpfsHash, size: i64) -> Self {
         Self {
-            path: path.clone(),
+            path: path.to_string(),
             hash: hash.clone(),
             size,
         }
     }
 
-    pub fn path(&self) -> &IpfsHash {
-        &self.path
+    pub fn path(&self) -> &str {
+        self.path.as_str()
     }
 
     pub fn hash(&self) -> &IpfsHash {
@@ -43,15 +46,21 @@ impl CatalogReference {
     }
 }
 
+/// Read-only connections kept open per catalog once released, so concurrent
+/// lookups do not need to open the database again.
+static MAX_IDLE_READERS: usize = 8;
+
+/// Changes go through a single read-write connection, while lookups borrow
+/// a read-only connection from a pool so they can run in parallel.
 pub struct Catalog {
     hash: IpfsHash,
-    connection: Connection,
+    path: PathBuf,
+    connection: Mutex<Connection>,
+    readers: Mutex<Vec<Connection>>,
     file: File,  // this is here just to avoid loosing the file
-}
-
-unsafe impl Sync for Catalog {
-    // Sqlite does not allow multithreading, and hence whatever client
-    // implementing QuantumFS MUST NOT write in the catalog databases concurrently.
+    upgraded: bool,
+    /// Private copy an older catalog was upgraded in, removed on drop unless kept.
+    upgrade: Mutex<Option<TempPath>>,
 }
 
 impl fmt::Debug for Catalog {
@@ -61,25 +70,14 @@ impl fmt::Debug for Catalog {
 }
 
 impl Catalog {
-    fn create_blank_catalog(cache_path: &Path) -> Result<File, QFSError> {
-        let mut tmpfile = NamedTempFile::new().unwrap();
+    fn create_blank_catalog(cache_path: &Path, root_folder: &DirectoryEntry) -> Result<File, QFSError> {
+        let mut tmpfile = NamedTempFile::new()?;
         let connection = Connection::open_with_flags(
             tmpfile.path(),
             OpenFlags::default(),
         ).map_err(QFSError::from)?;
         database::create_catalog(&connection)?;
-        let root_folder = DirectoryEntry {
-            path: IpfsHash::new(ipfs::hash_bytes("/".as_bytes()).as_str()).unwrap(),
-            parent: IpfsHash::new(ipfs::hash_bytes("".as_bytes()).as_str()).unwrap(),
-            hash: IpfsHash::new(ipfs::hash_bytes(&[]).as_str()).unwrap(),
-            flags: flags::DIRECTORY | flags::NESTED_CATALOG_ROOT,
-            size: 3,
-            mode: 16877,
-            mtime: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
-            name: "".to_string(),
-            symlink: "".to_string(),
-        };
-        database::add_directory_entry(&connection, &root_folder)?;
+        database::add_directory_entry(&connection, root_folder)?;
         tmpfile.flush()?;
         let mut data = Vec::new();
         tmpfile.as_file().read_to_end(&mut data)?;
@@ -90,46 +88,157 @@ impl Catalog {
             .map_err(QFSError::from)
     }
 
-    pub fn new(cache_path: &Path) -> Result<Self, QFSError> {
-        let file = Self::create_blank_catalog(cache_path)?;
+    fn from_file(file: File) -> Result<Self, QFSError> {
         let path = file.path()?;
-        let hash_str = path.as_path().file_name() .unwrap().to_str().unwrap();
-        let hash = IpfsHash::new(hash_str)?;
+        let hash = Self::hash_from_path(path.as_path())?;
         let connection = Connection::open_with_flags(
-            path,
+            path.as_path(),
             OpenFlags::default(),
         ).map_err(QFSError::from)?;
         let catalog = Self {
-            connection,
+            connection: Mutex::new(connection),
+            readers: Mutex::new(Vec::new()),
             hash,
+            path,
             file,
+            upgraded: false,
+            upgrade: Mutex::new(None),
         };
         Ok(catalog)
     }
 
+    pub fn new(cache_path: &Path) -> Result<Self, QFSError> {
+        let root_folder = DirectoryEntry {
+            path: IpfsHash::new(ipfs::hash_bytes("/".as_bytes()).as_str()).unwrap(),
+            parent: IpfsHash::new(ipfs::hash_bytes("".as_bytes()).as_str()).unwrap(),
+            hash: IpfsHash::new(ipfs::hash_bytes(&[]).as_str()).unwrap(),
+            flags: flags::DIRECTORY | flags::NESTED_CATALOG_ROOT,
+            size: 3,
+            mode: 16877,
+            mtime: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+            name: "".to_string(),
+            symlink: "".to_string(),
+            uid: None,
+            gid: None,
+            xattrs: Xattrs::new(),
+            hardlink_group: 0,
+            links: 1,
+        };
+        let file = Self::create_blank_catalog(cache_path, &root_folder)?;
+        Self::from_file(file)
+    }
+
+    /// Creates an empty catalog to be mounted on the directory `root_folder`.
+    pub fn new_nested(cache_path: &Path, root_folder: &DirectoryEntry) -> Result<Self, QFSError> {
+        let file = Self::create_blank_catalog(cache_path, root_folder)?;
+        Self::from_file(file)
+    }
+
     pub fn file(&self) -> &File {
         return &self.file
     }
 
+    fn hash_from_path(path: &Path) -> Result<IpfsHash, QFSError> {
+        let file_name = path.file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        IpfsHash::new(file_name)
+    }
+
+    /// Opens a catalog file. Catalogs written with an older schema revision
+    /// are upgraded in a private copy next to it, leaving the cached object
+    /// untouched. Catalogs newer than `database::SCHEMA_REVISION` are refused.
     pub fn load(path: &Path) -> Result<Self, QFSError> {
-        let file_name = path.file_name().unwrap().to_str().unwrap();
+        let hash = Self::hash_from_path(path)?;
+        let revision = {
+            let reader = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            database::schema_revision(&reader)?
+        };
+        if revision > database::SCHEMA_REVISION {
+            return Err(QFSError::UnsupportedSchema(revision));
+        }
+        let upgrade = if revision < database::SCHEMA_REVISION {
+            let directory = path.parent().unwrap_or_else(|| Path::new("."));
+            let mut copy = NamedTempFile::new_in(directory)?;
+            copy.write_all(fs::read(path)?.as_ref())?;
+            Some(copy.into_temp_path())
+        } else {
+            None
+        };
+        let path = upgrade.as_ref().map_or(path, |copy| copy.as_ref());
         let connection = Connection::open_with_flags(
             path,
             OpenFlags::default(),
         ).map_err(QFSError::from)?;
+        let upgraded = database::migrate(&connection)?;
         Ok(Self {
-            hash: IpfsHash::new(file_name)?,
-            connection,
+            hash,
+            path: path.to_owned(),
+            connection: Mutex::new(connection),
+            readers: Mutex::new(Vec::new()),
             file: File::open(path)?,
+            upgraded,
+            upgrade: Mutex::new(upgrade),
         })
     }
 
+    /// Path of the database, which is the private copy of upgraded catalogs.
+    pub fn database_path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// Keeps the private copy of an upgraded catalog once it is dropped,
+    /// so it can be published.
+    pub fn keep_upgrade(&self) -> Result<(), QFSError> {
+        if let Some(copy) = self.upgrade.lock().unwrap().take() {
+            copy.keep().map_err(|error| QFSError::from(error.error))?;
+        }
+        Ok(())
+    }
+
+    /// Runs a query on a read-only connection of the pool, opening a new one
+    /// when every pooled connection is in use.
+    fn read<T, F>(&self, query: F) -> Result<T, QFSError>
+        where F: FnOnce(&Connection) -> Result<T, QFSError>
+    {
+        let pooled = self.readers.lock().unwrap().pop();
+        let reader = match pooled {
+            Some(reader) => reader,
+            None => Connection::open_with_flags(
+                self.path.as_path(),
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?,
+        };
+        let result = query(&reader);
+        let mut readers = self.readers.lock().unwrap();
+        if readers.len() < MAX_IDLE_READERS {
+            readers.push(reader);
+        }
+        result
+    }
+
+    fn write<T, F>(&self, change: F) -> Result<T, QFSError>
+        where F: FnOnce(&Connection) -> Result<T, QFSError>
+    {
+        change(&self.connection.lock().unwrap())
+    }
+
     pub fn hash(&self) -> &IpfsHash {
         &self.hash
     }
 
+    /// Whether the catalog was upgraded when loaded, in which case its
+    /// content no longer matches its hash.
+    pub fn upgraded(&self) -> bool {
+        self.upgraded
+    }
+
+    pub fn schema_revision(&self) -> Result<i64, QFSError> {
+        self.read(database::schema_revision)
+    }
+
     pub fn list_nested(&self) -> Result<Vec<CatalogReference>, QFSError> {
-        database::list_nested(&self.connection)
+        self.read(database::list_nested)
     }
 
     pub fn find_nested_for_path(&self, needle_path: &str) -> Result<Option<CatalogReference>, QFSError> {
@@ -138,11 +247,10 @@ impl Catalog {
         let mut best_match_score: usize = 0;
         let real_needle_path = path::canonicalize_path(needle_path);
         for nested_catalog in catalog_refs.iter() {
-            let nested_catalog_string = nested_catalog.path().to_string();
-            let nested_catalog_path = nested_catalog_string.as_str();
+            let nested_catalog_path = nested_catalog.path();
             if real_needle_path.starts_with(nested_catalog_path) &&
                 nested_catalog_path.len() > best_match_score &&
-                path::is_sanitized(needle_path, nested_catalog_path)
+                path::is_sanitized(real_needle_path.as_str(), nested_catalog_path)
             {
                 best_match_score = nested_catalog_path.len();
                 best_match = Some(nested_catalog);
@@ -158,28 +266,78 @@ impl Catalog {
         let path = path::canonicalize_path(path);
         let real_path = path.as_str();
         let hash = ipfs::hash_bytes(real_path.as_bytes());
-        database::find_directory_entry(&self.connection, hash)
+        self.read(|connection| database::find_directory_entry(connection, hash))?
+            .ok_or_else(|| QFSError::NotFound(real_path.to_string()))
     }
 
     pub fn list_directory(&self, path: &str) -> Result<Vec<DirectoryEntry>, QFSError> {
         let real_path = path::canonicalize_path(path);
         let hash = ipfs::hash_bytes(real_path.as_bytes());
-        database::list_directory(&self.connection, hash)
+        self.read(|connection| database::list_directory(connection, hash))
     }
 
     pub fn add_directory_entry(&self, dirent: &DirectoryEntry) -> Result<(), QFSError> {
-        database::add_directory_entry(&self.connection, &dirent)
+        self.write(|connection| database::add_directory_entry(connection, dirent))
+    }
+
+    pub fn update_directory_entry(&self, dirent: &DirectoryEntry) -> Result<(), QFSError> {
+        self.write(|connection| database::update_directory_entry(connection, dirent))
+    }
+
+    pub fn remove_directory_entry(&self, path: &str) -> Result<(), QFSError> {
+        let real_path = path::canonicalize_path(path);
+        let hash = ipfs::hash_bytes(real_path.as_bytes());
+        self.write(|connection| database::remove_directory_entry(connection, hash))
+    }
+
+    pub fn list_chunks(&self, path: &str) -> Result<Vec<Chunk>, QFSError> {
+        let real_path = path::canonicalize_path(path);
+        let hash = ipfs::hash_bytes(real_path.as_bytes());
+        self.read(|connection| database::list_chunks(connection, hash))
+    }
+
+    /// Replaces the chunk list of the file at `path`.
+    pub fn update_chunks(&self, path: &str, chunks: &[Chunk]) -> Result<(), QFSError> {
+        let real_path = path::canonicalize_path(path);
+        let hash = ipfs::hash_bytes(real_path.as_bytes());
+        self.write(|connection| {
+            database::remove_chunks(connection, hash.clone())?;
+            database::add_chunks(connection, hash, chunks)
+        })
+    }
+
+    pub fn update_nested(&self, reference: &CatalogReference) -> Result<(), QFSError> {
+        self.write(|connection| database::update_nested(connection, reference))
+    }
+
+    pub fn remove_nested(&self, path: &str) -> Result<(), QFSError> {
+        self.write(|connection| database::remove_nested(connection, path))
+    }
+
+    pub fn count_entries(&self) -> Result<i64, QFSError> {
+        self.read(database::count_entries)
+    }
+
+    pub fn list_hardlinks(&self) -> Result<Vec<DirectoryEntry>, QFSError> {
+        self.read(database::list_hardlinks)
     }
 }
 
 
 #[cfg(test)]
 mod tests {
+    use crate::errors::QFSError;
     use crate::models::catalog::Catalog;
     use crate::models::directoryentry::{DirectoryEntry, flags};
+    use crate::operations::database;
     use crate::operations::ipfs::{self, IPFS};
+    use crate::operations::xattr::Xattrs;
     use crate::types::ipfs::IpfsHash;
+    use filepath::FilePath;
+    use rusqlite::Connection;
+    use std::fs;
     use std::path::Path;
+    use tempfile::tempdir;
 
     #[test]
     fn test_create_catalog_should_work() {
@@ -206,6 +364,11 @@ mod tests {
             mtime: 0,
             name: "file1".to_string(),
             symlink: "".to_string(),
+            uid: Some(0),
+            gid: Some(0),
+            xattrs: Xattrs::new(),
+            hardlink_group: 0,
+            links: 1,
         };
         let result = catalog.add_directory_entry(&dirent);
         assert!(result.is_ok(), format!("{:?}", result));
@@ -213,4 +376,152 @@ mod tests {
         assert_eq!(files.len(), 1);
         assert_eq!(files[0], dirent);
     }
+
+    #[test]
+    fn test_update_entry_should_replace_it() {
+        let cache_path = Path::new("/tmp");
+        let catalog = Catalog::new(cache_path).unwrap();
+        let mut dirent = DirectoryEntry {
+            path: IpfsHash::new(ipfs::hash_bytes("/file1".as_bytes()).as_str()).unwrap(),
+            parent: IpfsHash::new(ipfs::hash_bytes("/".as_bytes()).as_str()).unwrap(),
+            hash: IpfsHash::new(ipfs::hash_bytes("version 1".as_bytes()).as_str()).unwrap(),
+            flags: flags::FILE,
+            size: 9,
+            mode: 0,
+            mtime: 0,
+            name: "file1".to_string(),
+            symlink: "".to_string(),
+            uid: Some(0),
+            gid: Some(0),
+            xattrs: Xattrs::new(),
+            hardlink_group: 0,
+            links: 1,
+        };
+        catalog.add_directory_entry(&dirent).unwrap();
+        dirent.hash = IpfsHash::new(ipfs::hash_bytes("version 2".as_bytes()).as_str()).unwrap();
+        catalog.update_directory_entry(&dirent).unwrap();
+        let files = catalog.list_directory("/").unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0], dirent);
+    }
+
+    type CatalogCheck = fn(&Catalog) -> Result<(), QFSError>;
+
+    fn find_root(catalog: &Catalog) -> Result<(), QFSError> {
+        catalog.find_directory_entry("/").map(|_| ())
+    }
+
+    fn list_root(catalog: &Catalog) -> Result<(), QFSError> {
+        catalog.list_directory("/").map(|_| ())
+    }
+
+    fn list_nested(catalog: &Catalog) -> Result<(), QFSError> {
+        catalog.list_nested().map(|_| ())
+    }
+
+    fn find_nested(catalog: &Catalog) -> Result<(), QFSError> {
+        catalog.find_nested_for_path("/dir/file1").map(|_| ())
+    }
+
+    fn list_chunks(catalog: &Catalog) -> Result<(), QFSError> {
+        catalog.list_chunks("/file1").map(|_| ())
+    }
+
+    /// Catalogs a malicious or buggy publisher could produce, together with
+    /// the operation that must fail on them.
+    fn corrupt_catalogs() -> Vec<(&'static str, String, CatalogCheck)> {
+        let valid_hash = ipfs::hash_bytes(b"hello world");
+        let root = ipfs::hash_bytes(b"/");
+        let file1 = ipfs::hash_bytes(b"/file1");
+        vec![
+            ("null hash", String::from("UPDATE catalog SET hash = NULL;"), find_root),
+            ("invalid hash", String::from("UPDATE catalog SET hash = 'not a hash';"), find_root),
+            ("invalid path", String::from("UPDATE catalog SET parent = 'not a hash';"), find_root),
+            ("text size", String::from("UPDATE catalog SET size = 'big';"), find_root),
+            ("null name", String::from("UPDATE catalog SET name = NULL;"), find_root),
+            ("truncated xattrs", String::from("UPDATE catalog SET xattr = X'000000';"), find_root),
+            ("text xattrs", String::from("UPDATE catalog SET xattr = 'user.a';"), find_root),
+            ("missing columns", String::from(
+                "DROP TABLE catalog; CREATE TABLE catalog (path TEXT, parent TEXT);"), find_root),
+            ("missing catalog table", String::from("DROP TABLE catalog;"), list_root),
+            ("invalid child", format!(
+                "INSERT INTO catalog (path, parent, hash, flags, size, mode, mtime, name, symlink) \
+                    VALUES ('{}', '{}', 'bad', 4, 0, 0, 0, 'file1', '');", file1, root), list_root),
+            ("nested invalid hash", String::from(
+                "INSERT INTO nested_catalogs VALUES ('/dir', 'garbage', 10);"), list_nested),
+            ("nested text size", format!(
+                "INSERT INTO nested_catalogs VALUES ('/dir', '{}', 'ten');", valid_hash), find_nested),
+            ("nested null path", format!(
+                "INSERT INTO nested_catalogs VALUES (NULL, '{}', 10);", valid_hash), find_nested),
+            ("missing nested table", String::from("DROP TABLE nested_catalogs;"), find_nested),
+            ("chunk invalid hash", format!(
+                "INSERT INTO chunks VALUES ('{}', 0, 10, 'garbage');", file1), list_chunks),
+            ("chunk null offset", format!(
+                "INSERT INTO chunks VALUES ('{}', NULL, 10, '{}');", file1, valid_hash), list_chunks),
+            ("missing chunks table", String::from("DROP TABLE chunks;"), list_chunks),
+        ]
+    }
+
+    #[test]
+    fn corrupt_catalogs_should_be_rejected() {
+        for (name, corruption, check) in corrupt_catalogs() {
+            let cache_dir = tempdir().unwrap();
+            let path = Catalog::new(cache_dir.path()).unwrap().file().path().unwrap();
+            Connection::open(path.as_path()).unwrap().execute_batch(corruption.as_str()).unwrap();
+            let catalog = Catalog::load(path.as_path()).unwrap();
+            assert!(check(&catalog).is_err(), "{} catalog was accepted", name);
+        }
+    }
+
+    #[test]
+    fn garbage_catalogs_should_be_rejected() {
+        let cache_dir = tempdir().unwrap();
+        let path = cache_dir.path().join(ipfs::hash_bytes(b"this is not a catalog"));
+        fs::write(path.as_path(), "this is not a catalog").unwrap();
+        match Catalog::load(path.as_path()) {
+            Err(QFSError::Database(_)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert!(Catalog::load(cache_dir.path().join("not a hash").as_path()).is_err());
+    }
+
+    #[test]
+    fn load_should_upgrade_unversioned_catalogs() {
+        let cache_dir = tempdir().unwrap();
+        let path = Catalog::new(cache_dir.path()).unwrap().file().path().unwrap();
+        Connection::open(path.as_path()).unwrap()
+            .execute_batch("DROP TABLE properties; DROP TABLE chunks; \
+                UPDATE catalog SET uid = NULL, gid = NULL, hardlinks = NULL;").unwrap();
+
+        let legacy = fs::read(path.as_path()).unwrap();
+
+        let catalog = Catalog::load(path.as_path()).unwrap();
+        assert!(catalog.upgraded());
+        assert_ne!(catalog.database_path(), path.as_path());
+        assert_eq!(fs::read(path.as_path()).unwrap(), legacy);
+        assert_eq!(catalog.schema_revision().unwrap(), database::SCHEMA_REVISION);
+        assert!(catalog.list_chunks("/file1").unwrap().is_empty());
+        let root = catalog.find_directory_entry("/").unwrap();
+        assert!(root.is_directory());
+        assert_eq!((root.uid, root.gid), (None, None));
+        assert_eq!((root.hardlink_group, root.links), (0, 1));
+        let copy = catalog.database_path().to_owned();
+        drop(catalog);
+        assert!(!copy.exists());
+
+        let catalog = Catalog::load(path.as_path()).unwrap();
+        assert!(catalog.upgraded());
+    }
+
+    #[test]
+    fn load_should_refuse_newer_catalogs() {
+        let cache_dir = tempdir().unwrap();
+        let path = Catalog::new(cache_dir.path()).unwrap().file().path().unwrap();
+        Connection::open(path.as_path()).unwrap()
+            .execute_batch("UPDATE properties SET value = '99' WHERE key = 'schema_revision';").unwrap();
+        match Catalog::load(path.as_path()) {
+            Err(QFSError::UnsupportedSchema(99)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
 }
@@ -0,0 +1,81 @@
+use rusqlite::Row;
+
+use crate::errors::QFSError;
+use crate::operations::ipfs;
+use crate::types::ipfs::IpfsHash;
+
+pub static DATABASE_FIELDS: &str = "offset, size, hash";
+
+/// Files bigger than this are split into chunks of this size when published.
+pub static DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024;
+
+
+/// A slice of a chunked file, stored as an object of its own.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Chunk {
+    pub offset: i64,
+    pub size: i64,
+    pub hash: IpfsHash,
+}
+
+impl Chunk {
+    pub fn from_sql_row(row: &Row) -> Result<Self, QFSError> {
+        let hash: String = row.get(2)?;
+        Ok(Self {
+            offset: row.get(0)?,
+            size: row.get(1)?,
+            hash: IpfsHash::new(hash.as_str())?,
+        })
+    }
+
+    pub fn end(&self) -> i64 {
+        self.offset + self.size
+    }
+
+    pub fn overlaps(&self, offset: i64, size: i64) -> bool {
+        self.offset < offset + size && offset < self.end()
+    }
+}
+
+/// Content hash of a chunked file, computed over the hashes of its chunks.
+pub fn chunk_list_hash(chunks: &[Chunk]) -> Result<IpfsHash, QFSError> {
+    let list = chunks.iter()
+        .map(|chunk| chunk.hash.to_string())
+        .collect::<Vec<String>>()
+        .join("\n");
+    IpfsHash::new(ipfs::hash_bytes(list.as_bytes()).as_str())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::models::chunk::{Chunk, chunk_list_hash};
+    use crate::operations::ipfs;
+    use crate::types::ipfs::IpfsHash;
+
+    fn chunk(offset: i64, size: i64, content: &str) -> Chunk {
+        Chunk {
+            offset,
+            size,
+            hash: IpfsHash::new(ipfs::hash_bytes(content.as_bytes()).as_str()).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let chunk = chunk(10, 10, "chunk");
+        assert!(chunk.overlaps(0, 11));
+        assert!(chunk.overlaps(19, 100));
+        assert!(!chunk.overlaps(0, 10));
+        assert!(!chunk.overlaps(20, 10));
+    }
+
+    #[test]
+    fn test_chunk_list_hash_depends_on_the_order() {
+        let first = chunk(0, 5, "first");
+        let second = chunk(5, 5, "second");
+        let hash = chunk_list_hash(&[first.clone(), second.clone()]).unwrap();
+        assert_eq!(hash, chunk_list_hash(&[first.clone(), second.clone()]).unwrap());
+        assert_ne!(hash, chunk_list_hash(&[second, first]).unwrap());
+    }
+}
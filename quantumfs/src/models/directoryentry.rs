@@ -1,8 +1,49 @@
+use std::convert::TryInto;
+
+use base58::FromBase58;
 use rusqlite::Row;
 
+use crate::errors::QFSError;
+use crate::operations::{ipfs, path};
+use crate::operations::xattr::{self, Xattrs};
 use crate::types::ipfs::IpfsHash;
 
-pub static DATABASE_FIELDS: &str = "path, parent, hash, flags, size, mode, mtime, name, symlink";
+pub static DATABASE_FIELDS: &str = "path, parent, hash, flags, size, mode, mtime, name, symlink, uid, gid, xattr, hardlinks";
+
+/// Inode of the root directory, as expected by FUSE.
+pub static ROOT_INODE: u64 = 1;
+
+/// Derives an inode number from the first 64 bits of the digest of a hash,
+/// keeping clear of the root inode. Inodes of distinct paths may collide,
+/// so mounts check the key each inode was derived from.
+fn hash_inode(hash: &str) -> u64 {
+    let bytes = hash.from_base58().unwrap_or_default();
+    // skip the multihash header, made of the hash function and digest length
+    let inode = bytes.get(2..10)
+        .and_then(|digest| digest.try_into().ok())
+        .map(u64::from_be_bytes)
+        .unwrap_or(0);
+    if inode <= ROOT_INODE { inode + ROOT_INODE + 1 } else { inode }
+}
+
+/// Key an entry without hardlinks derives its inode from, its hashed path.
+pub fn path_inode_key(entry_path: &str) -> String {
+    ipfs::hash_bytes(path::canonicalize_path(entry_path).as_bytes())
+}
+
+fn key_inode(key: &str) -> u64 {
+    if key == ipfs::hash_bytes(b"/").as_str() {
+        ROOT_INODE
+    } else {
+        hash_inode(key)
+    }
+}
+
+/// Inode of an entry without hardlinks, which only depends on its path so
+/// that it is the same across mounts, revisions and layers of an overlay.
+pub fn path_inode(entry_path: &str) -> u64 {
+    key_inode(path_inode_key(entry_path).as_str())
+}
 
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -16,26 +57,69 @@ pub struct DirectoryEntry {
     pub mtime: i64,
     pub name: String,
     pub symlink: String,
+    /// Owners of the entry, unknown for entries published before owners
+    /// were recorded.
+    pub uid: Option<i64>,
+    pub gid: Option<i64>,
+    pub xattrs: Xattrs,
+    /// Entries of the revision sharing a non-zero group are hardlinks
+    /// to each other. The group is 0 for entries without other links.
+    pub hardlink_group: i64,
+    pub links: i64,
 }
 
 impl DirectoryEntry {
-    pub fn from_sql_row(row: &Row) -> Self {
-        let path: String = row.get(0).unwrap();
-        let parent: String = row.get(1).unwrap();
-        let hash: String = row.get(2).unwrap();
-        Self {
-            path: IpfsHash::new(path.as_str()).unwrap(),
-            parent: IpfsHash::new(parent.as_str()).unwrap(),
-            hash: IpfsHash::new(hash.as_str()).unwrap(),
-            flags: row.get(3).unwrap(),
-            size: row.get(4).unwrap(),
-            mode: row.get(5).unwrap(),
-            mtime: row.get(6).unwrap(),
-            name: row.get(7).unwrap(),
-            symlink: row.get(8).unwrap(),
+    pub fn from_sql_row(row: &Row) -> Result<Self, QFSError> {
+        let path: String = row.get(0)?;
+        let parent: String = row.get(1)?;
+        let hash: String = row.get(2)?;
+        let xattrs: Option<Vec<u8>> = row.get(11)?;
+        let hardlinks: i64 = row.get(12)?;
+        Ok(Self {
+            path: IpfsHash::new(path.as_str())?,
+            parent: IpfsHash::new(parent.as_str())?,
+            hash: IpfsHash::new(hash.as_str())?,
+            flags: row.get(3)?,
+            size: row.get(4)?,
+            mode: row.get(5)?,
+            mtime: row.get(6)?,
+            name: row.get(7)?,
+            symlink: row.get(8)?,
+            uid: row.get(9)?,
+            gid: row.get(10)?,
+            xattrs: match xattrs {
+                Some(data) => xattr::decode(data.as_slice())?,
+                None => Xattrs::new(),
+            },
+            hardlink_group: hardlinks >> 32,
+            links: hardlinks & 0xffff_ffff,
+        })
+    }
+
+    /// Packs the hardlink group and the link count into the `hardlinks`
+    /// column, the group in the upper 32 bits.
+    pub fn hardlinks(&self) -> i64 {
+        (self.hardlink_group << 32) | (self.links & 0xffff_ffff)
+    }
+
+    pub fn is_hardlink(&self) -> bool {
+        self.hardlink_group > 0
+    }
+
+    /// Identifies the file behind the entry: its path, or its hardlink group.
+    pub fn inode_key(&self) -> String {
+        if self.is_hardlink() {
+            ipfs::hash_bytes(format!("hardlink:{}", self.hardlink_group).as_bytes())
+        } else {
+            self.path.to_string()
         }
     }
 
+    /// Stable inode of the entry. Hardlinks share the inode of their group.
+    pub fn inode(&self) -> u64 {
+        key_inode(self.inode_key().as_str())
+    }
+
     pub fn is_directory(&self) -> bool {
         (self.flags & flags::DIRECTORY) > 0
     }
@@ -51,6 +135,10 @@ impl DirectoryEntry {
     pub fn is_nested_catalog_root(&self) -> bool {
         (self.flags & flags::NESTED_CATALOG_ROOT) > 0
     }
+
+    pub fn is_chunked(&self) -> bool {
+        (self.flags & flags::CHUNKED) > 0
+    }
 }
 
 
@@ -59,4 +147,56 @@ pub mod flags {
     pub static FILE: i64 = 4;
     pub static LINK: i64 = 8;
     pub static NESTED_CATALOG_ROOT: i64 = 32;
+    pub static CHUNKED: i64 = 64;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::models::directoryentry::{DirectoryEntry, flags, path_inode, ROOT_INODE};
+    use crate::operations::ipfs;
+    use crate::operations::xattr::Xattrs;
+    use crate::types::ipfs::IpfsHash;
+
+    fn hash(content: &str) -> IpfsHash {
+        IpfsHash::new(ipfs::hash_bytes(content.as_bytes()).as_str()).unwrap()
+    }
+
+    fn dirent(path: &str, hardlink_group: i64) -> DirectoryEntry {
+        DirectoryEntry {
+            path: hash(path),
+            parent: hash("/dir"),
+            hash: hash("content"),
+            flags: flags::FILE,
+            size: 7,
+            mode: 33188,
+            mtime: 0,
+            name: path.rsplit('/').next().unwrap().to_string(),
+            symlink: "".to_string(),
+            uid: Some(0),
+            gid: Some(0),
+            xattrs: Xattrs::new(),
+            hardlink_group,
+            links: if hardlink_group > 0 { 2 } else { 1 },
+        }
+    }
+
+    #[test]
+    fn inode_should_depend_on_the_path() {
+        assert_eq!(path_inode("/"), ROOT_INODE);
+        assert_eq!(dirent("/dir/file1", 0).inode(), path_inode("/dir/file1"));
+        assert_eq!(path_inode("/dir/file1"), path_inode("/dir//file1/"));
+        assert_ne!(dirent("/dir/file1", 0).inode(), dirent("/dir/file2", 0).inode());
+        assert!(dirent("/dir/file1", 0).inode() > ROOT_INODE);
+    }
+
+    #[test]
+    fn hardlinks_should_share_their_inode() {
+        assert_eq!(dirent("/dir/file1", 1).inode(), dirent("/dir/file2", 1).inode());
+        assert_ne!(dirent("/dir/file1", 1).inode(), dirent("/dir/file2", 2).inode());
+        assert_ne!(dirent("/dir/file1", 1).inode(), path_inode("/dir/file1"));
+        let mut other = dirent("/other/file1", 1);
+        other.parent = hash("/other");
+        assert_eq!(dirent("/dir/file1", 1).inode(), other.inode());
+    }
 }
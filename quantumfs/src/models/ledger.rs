@@ -0,0 +1,226 @@
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Mutex;
+
+use rusqlite::{Connection, NO_PARAMS, OpenFlags, ToSql, TransactionBehavior};
+
+use crate::errors::QFSError;
+use crate::models::revision::RevisionTag;
+use crate::types::ipfs::IpfsHash;
+
+pub static GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000";
+
+/// Keeps the history of root catalog hashes of a repository, mirroring the
+/// `QuantumFS` contract: revisions are numbered from 1 and revision 0 stands
+/// for an empty history.
+pub trait RevisionLedger: Debug + Send + Sync {
+    fn current_revision(&self) -> Result<RevisionTag, QFSError>;
+
+    fn get_revision(&self, revision: u128) -> Result<RevisionTag, QFSError>;
+
+    fn total_revisions(&self) -> Result<u128, QFSError>;
+
+    /// Appends a revision made from the `base` revision, refusing it if the
+    /// history has moved past that base in the meantime.
+    fn add_revision(&self, hash: &IpfsHash, base: u128) -> Result<RevisionTag, QFSError>;
+
+    fn evict(&self) -> Result<(), QFSError>;
+
+    /// Every revision of the history, oldest first.
+    fn history(&self) -> Result<Vec<RevisionTag>, QFSError> {
+        (1..=self.total_revisions()?)
+            .map(|revision| self.get_revision(revision))
+            .collect()
+    }
+}
+
+lazy_static! {
+    static ref CREATE_REVISIONS: String = String::from(
+    "CREATE TABLE IF NOT EXISTS revisions \
+        (owner TEXT, revision INTEGER, hash TEXT, \
+        CONSTRAINT pk_revisions PRIMARY KEY (owner, revision));"
+    );
+
+    static ref COUNT_REVISIONS: String = String::from(
+    "SELECT COUNT(*) \
+        FROM revisions \
+        WHERE owner = ?;"
+    );
+
+    static ref FIND_REVISION: String = String::from(
+    "SELECT hash \
+        FROM revisions \
+        WHERE owner = ? AND revision = ?;"
+    );
+
+    static ref INSERT_REVISION: String = String::from(
+    "INSERT INTO revisions (owner, revision, hash) \
+        VALUES (?, ?, ?);"
+    );
+
+    static ref DELETE_REVISIONS: String = String::from(
+    "DELETE FROM revisions \
+        WHERE owner = ?;"
+    );
+}
+
+/// Ledger kept in a local sqlite database, either in memory or in a file.
+#[derive(Debug)]
+pub struct LocalLedger {
+    owner: String,
+    connection: Mutex<Connection>,
+}
+
+impl LocalLedger {
+    fn from_connection(connection: Connection, owner: &str) -> Result<Self, QFSError> {
+        connection.execute(CREATE_REVISIONS.as_str(), NO_PARAMS)?;
+        Ok(Self {
+            owner: owner.to_string(),
+            connection: Mutex::new(connection),
+        })
+    }
+
+    pub fn open(path: &Path, owner: &str) -> Result<Self, QFSError> {
+        let connection = Connection::open_with_flags(path, OpenFlags::default())?;
+        Self::from_connection(connection, owner)
+    }
+
+    pub fn in_memory(owner: &str) -> Result<Self, QFSError> {
+        Self::from_connection(Connection::open_in_memory()?, owner)
+    }
+}
+
+impl RevisionLedger for LocalLedger {
+    fn current_revision(&self) -> Result<RevisionTag, QFSError> {
+        match self.total_revisions()? {
+            0 => Ok(RevisionTag::new(&IpfsHash::new(GENESIS_HASH)?, 0)),
+            total => self.get_revision(total),
+        }
+    }
+
+    fn get_revision(&self, revision: u128) -> Result<RevisionTag, QFSError> {
+        if revision == 0 {
+            return Err(QFSError::ledger("Revision number must be greater than zero"));
+        }
+        if revision > self.total_revisions()? {
+            return Err(QFSError::ledger("Invalid revision"));
+        }
+        let connection = self.connection.lock().unwrap();
+        let hash: String = connection.query_row(
+            FIND_REVISION.as_str(),
+            &[&self.owner as &dyn ToSql, &(revision as i64)],
+            |row| row.get(0),
+        )?;
+        Ok(RevisionTag::new(&IpfsHash::new(hash.as_str())?, revision))
+    }
+
+    fn total_revisions(&self) -> Result<u128, QFSError> {
+        let connection = self.connection.lock().unwrap();
+        let total: i64 = connection.query_row(COUNT_REVISIONS.as_str(), &[&self.owner], |row| row.get(0))?;
+        Ok(total as u128)
+    }
+
+    fn add_revision(&self, hash: &IpfsHash, base: u128) -> Result<RevisionTag, QFSError> {
+        let mut connection = self.connection.lock().unwrap();
+        // other processes may share the ledger file, so check and insert at once
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let total: i64 = transaction.query_row(COUNT_REVISIONS.as_str(), &[&self.owner], |row| row.get(0))?;
+        if total as u128 != base {
+            return Err(QFSError::Conflict { base, current: total as u128 });
+        }
+        let revision = base + 1;
+        transaction.execute(
+            INSERT_REVISION.as_str(),
+            &[&self.owner as &dyn ToSql, &(revision as i64), &hash.to_string()],
+        )?;
+        transaction.commit()?;
+        Ok(RevisionTag::new(hash, revision))
+    }
+
+    fn evict(&self) -> Result<(), QFSError> {
+        self.connection.lock().unwrap().execute(DELETE_REVISIONS.as_str(), &[&self.owner])?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use tempfile::tempdir;
+
+    use crate::errors::QFSError;
+    use crate::models::ledger::{GENESIS_HASH, LocalLedger, RevisionLedger};
+    use crate::types::ipfs::IpfsHash;
+
+    fn hash() -> IpfsHash {
+        IpfsHash::new("QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4").unwrap()
+    }
+
+    #[test]
+    fn empty_ledger_should_return_genesis() {
+        let ledger = LocalLedger::in_memory("owner").unwrap();
+        let tag = ledger.current_revision().unwrap();
+        assert_eq!(tag.hash().as_ref(), GENESIS_HASH);
+        assert_eq!(tag.revision(), 0);
+        assert!(ledger.get_revision(0).is_err());
+        assert!(ledger.get_revision(1).is_err());
+    }
+
+    #[test]
+    fn add_revision_should_work() {
+        let ledger = LocalLedger::in_memory("owner").unwrap();
+        let tag = ledger.add_revision(&hash(), 0).unwrap();
+        assert_eq!(tag.revision(), 1);
+        assert_eq!(ledger.total_revisions().unwrap(), 1);
+        assert_eq!(ledger.current_revision().unwrap().hash(), &hash());
+        assert_eq!(ledger.get_revision(1).unwrap().hash(), &hash());
+        assert_eq!(ledger.history().unwrap().len(), 1);
+        ledger.evict().unwrap();
+        assert_eq!(ledger.total_revisions().unwrap(), 0);
+    }
+
+    #[test]
+    fn file_ledger_should_keep_owners_apart() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("ledger");
+        LocalLedger::open(path.as_path(), "owner").unwrap().add_revision(&hash(), 0).unwrap();
+        let ledger = LocalLedger::open(path.as_path(), "owner").unwrap();
+        assert_eq!(ledger.total_revisions().unwrap(), 1);
+        let other = LocalLedger::open(path.as_path(), "other").unwrap();
+        assert_eq!(other.total_revisions().unwrap(), 0);
+    }
+
+    #[test]
+    fn add_revision_should_refuse_an_outdated_base() {
+        let ledger = LocalLedger::in_memory("owner").unwrap();
+        ledger.add_revision(&hash(), 0).unwrap();
+        match ledger.add_revision(&hash(), 0) {
+            Err(QFSError::Conflict { base, current }) => assert_eq!((base, current), (0, 1)),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert!(ledger.add_revision(&hash(), 2).is_err());
+        assert_eq!(ledger.add_revision(&hash(), 1).unwrap().revision(), 2);
+    }
+
+    #[test]
+    fn concurrent_publishers_should_not_push_on_the_same_base() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("ledger");
+        LocalLedger::open(path.as_path(), "owner").unwrap();
+        let barrier = Arc::new(Barrier::new(4));
+        let threads: Vec<_> = (0..4).map(|_| {
+            let (path, barrier) = (path.clone(), barrier.clone());
+            thread::spawn(move || {
+                let ledger = LocalLedger::open(path.as_path(), "owner").unwrap();
+                barrier.wait();
+                ledger.add_revision(&hash(), 0).is_ok()
+            })
+        }).collect();
+        let pushed = threads.into_iter().map(|thread| thread.join().unwrap()).filter(|&ok| ok).count();
+        assert_eq!(pushed, 1);
+        assert_eq!(LocalLedger::open(path.as_path(), "owner").unwrap().total_revisions().unwrap(), 1);
+    }
+}
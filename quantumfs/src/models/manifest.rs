@@ -4,46 +4,108 @@ use web3::types::Address;
 use web3::Web3;
 
 use crate::errors::QFSError;
+use crate::models::ledger::RevisionLedger;
 use crate::models::revision::RevisionTag;
 use crate::operations::ethereum;
+use crate::types::ipfs::IpfsHash;
 
+/// Ledger of the file system `name` of an account, kept by the contract.
+///
+/// Anyone can read the file system of `owner`, new revisions are signed by `account`.
 #[derive(Debug)]
 pub struct Manifest {
-    address: Address,
+    owner: Address,
+    account: Option<Address>,
+    name: String,
     contract: Contract<Http>,
     web3: Web3<Http>,
 }
 
 impl Manifest {
-    pub fn new(address: Address, contract_address: Address, web3_url: &str) -> Self {
+    pub fn new(owner: Address, name: &str, contract_address: Address, web3_url: &str) -> Self {
         let web3 = ethereum::get_web3(web3_url);
         let contract = ethereum::get_contract(&web3, contract_address);
         Self {
-            address,
+            owner,
+            account: None,
+            name: name.to_string(),
             contract,
             web3,
         }
     }
 
-    pub fn fetch_revision(&self, revision: u128) -> Result<RevisionTag, QFSError> {
-        let (hash, revision) = ethereum::fetch_revision(&self.contract, self.address, revision)?;
+    /// Signs new revisions with `account`.
+    pub fn with_account(mut self, account: Address) -> Self {
+        self.account = Some(account);
+        self
+    }
+
+    fn signing_account(&self) -> Result<Address, QFSError> {
+        self.account.ok_or_else(|| QFSError::ledger("No account to sign revisions with"))
+    }
+
+    /// Checks that the signing account is the owner or one of its publishers,
+    /// so that pushes fail before sending a transaction the contract reverts.
+    fn publishing_account(&self) -> Result<Address, QFSError> {
+        let account = self.signing_account()?;
+        if account != self.owner && !ethereum::is_publisher(&self.contract, self.owner, &self.name, account)? {
+            return Err(QFSError::ledger(&format!("{:x} cannot publish to the file system of {:x}", account, self.owner)));
+        }
+        Ok(account)
+    }
+
+    /// Evictions are reserved to the owner, publishers can only add revisions.
+    fn owner_account(&self) -> Result<Address, QFSError> {
+        let account = self.signing_account()?;
+        if account != self.owner {
+            return Err(QFSError::ledger(&format!("Only {:x} can evict its file system", self.owner)));
+        }
+        Ok(account)
+    }
+}
+
+impl RevisionLedger for Manifest {
+    fn current_revision(&self) -> Result<RevisionTag, QFSError> {
+        let (hash, revision) = ethereum::fetch_last_revision(&self.contract, self.owner, &self.name)?;
         Ok(RevisionTag::new(&hash, revision))
     }
 
-    pub fn fetch_last_revision(&self) -> Result<RevisionTag, QFSError> {
-        let (hash, revision) = ethereum::fetch_last_revision(&self.contract, self.address)?;
+    fn get_revision(&self, revision: u128) -> Result<RevisionTag, QFSError> {
+        let (hash, revision) = ethereum::fetch_revision(&self.contract, self.owner, &self.name, revision)?;
         Ok(RevisionTag::new(&hash, revision))
     }
-}
 
+    fn total_revisions(&self) -> Result<u128, QFSError> {
+        ethereum::fetch_total_revisions(&self.contract, self.owner, &self.name)
+    }
+
+    fn add_revision(&self, hash: &IpfsHash, base: u128) -> Result<RevisionTag, QFSError> {
+        let account = self.publishing_account()?;
+        let (hash, revision) = ethereum::push_revision(&self.contract, account, self.owner, &self.name, base, hash)?;
+        Ok(RevisionTag::new(&hash, revision))
+    }
+
+    fn evict(&self) -> Result<(), QFSError> {
+        ethereum::evict(&self.contract, self.owner_account()?, &self.name)
+    }
+
+    fn history(&self) -> Result<Vec<RevisionTag>, QFSError> {
+        let history = ethereum::fetch_revision_history(&self.web3, &self.contract, self.owner, &self.name)?;
+        Ok(history.iter().map(|(hash, revision)| RevisionTag::new(hash, *revision)).collect())
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use crate::models::ledger::RevisionLedger;
     use crate::models::manifest::Manifest;
-    use crate::operations::ethereum::tests::{TEST_CONTRACT, TEST_WEB3, coinbase};
+    use crate::types::ipfs::IpfsHash;
+    use web3::types::Address;
+    use crate::operations::ethereum::tests::{NAME, TEST_CONTRACT, TEST_WEB3, coinbase};
 
     fn create_manifest() -> Manifest {
         Manifest::new(coinbase(&TEST_WEB3),
+                      NAME,
                       TEST_CONTRACT.address(),
         "http://127.0.0.1:7545")
     }
@@ -51,15 +113,29 @@ mod tests {
     #[test]
     fn manifest_instantiation_should_work() {
         let manifest = create_manifest();
-        let tag = manifest.fetch_last_revision().unwrap();
+        let tag = manifest.current_revision().unwrap();
         assert_eq!(tag.hash().to_string().as_str(), "0000000000000000000000000000000000000000000000");
         assert_eq!(tag.revision(), 0);
     }
 
+    #[test]
+    fn add_revision_without_an_account_should_fail() {
+        let hash = IpfsHash::new("QmPZ9gcCEpqKTo6aq61g2nXGUhM4iCL3ewB6LDXZCtioEB").unwrap();
+        let manifest = Manifest::new(Address::from_low_u64_be(1), NAME, Address::zero(), "http://127.0.0.1:7545");
+        assert!(manifest.add_revision(&hash, 0).is_err());
+    }
+
+    #[test]
+    fn evict_without_the_owner_account_should_fail() {
+        let manifest = Manifest::new(Address::from_low_u64_be(1), NAME, Address::zero(), "http://127.0.0.1:7545")
+            .with_account(Address::from_low_u64_be(2));
+        assert!(manifest.evict().is_err());
+    }
+
     #[test]
     fn manifest_instantiation_with_invalid_revision_should_fail() {
         let manifest = create_manifest();
-        let tag = manifest.fetch_revision(100000);
+        let tag = manifest.get_revision(100000);
         assert!(tag.is_err());
     }
 }
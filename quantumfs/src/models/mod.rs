@@ -1,5 +1,9 @@
+pub mod ledger;
 pub mod manifest;
 pub mod repository;
+pub mod cache;
 pub mod catalog;
+pub mod chunk;
 pub mod revision;
 pub mod directoryentry;
+pub mod transaction;
@@ -1,50 +1,79 @@
 use std::path::{Path, PathBuf};
-
-use web3::transports::Http;
-use web3::types::Address;
-use web3::Web3;
+use std::sync::Arc;
 
 use crate::errors::QFSError;
-use crate::models::manifest::Manifest;
+use crate::models::cache::CacheManager;
+use crate::models::ledger::RevisionLedger;
 use crate::models::revision::{Revision, RevisionTag};
-use crate::operations::ethereum;
-use crate::operations::ipfs::IPFS;
+use crate::operations::store::ObjectStore;
+
+/// Name of the file system used when none is given.
+pub static DEFAULT_NAME: &str = "default";
 
+/// One of the named file systems of an account. The ledger must keep the
+/// history of that same file system.
 #[derive(Debug)]
 pub struct Repository {
-    manifest: Manifest,
-    web3: Web3<Http>,
-    ipfs_data: (String, u16),
+    name: String,
+    ledger: Box<dyn RevisionLedger>,
+    store: Arc<dyn ObjectStore>,
     cache_dir: PathBuf,
+    cache: Option<Arc<CacheManager>>,
 }
 
 impl Repository {
-    pub fn new(client_address: Address, contract_address: Address, cache_dir: &Path,
-               web3_url: &str, ipfs_server: &str, ipfs_port: u16) -> Self {
+    pub fn new(name: &str, ledger: Box<dyn RevisionLedger>, store: Arc<dyn ObjectStore>, cache_dir: &Path) -> Self {
         Self {
-            manifest: Manifest::new(client_address, contract_address, web3_url),
-            web3: ethereum::get_web3(web3_url),
-            ipfs_data: (ipfs_server.to_string(), ipfs_port),
+            name: name.to_string(),
+            ledger,
+            store,
             cache_dir: PathBuf::from(cache_dir),
+            cache: None,
+        }
+    }
+
+    /// Makes every revision opened from now on keep its objects under the
+    /// given cache manager.
+    pub fn with_cache(mut self, cache: Arc<CacheManager>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    fn attach_cache(&self, mut revision: Revision) -> Result<Revision, QFSError> {
+        if let Some(ref cache) = self.cache {
+            revision.set_cache(cache.clone())?;
         }
+        Ok(revision)
     }
 
     fn fetch_last_revision_tag(&self) -> Result<RevisionTag, QFSError> {
-        self.manifest.fetch_last_revision()
+        self.ledger.current_revision()
     }
 
     fn fetch_revision_tag(&self, revision_number: u128) -> Result<RevisionTag, QFSError> {
-        self.manifest.fetch_revision(revision_number)
+        self.ledger.get_revision(revision_number)
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn store(&self) -> Arc<dyn ObjectStore> {
+        self.store.clone()
     }
 
-    fn get_ipfs(&self) -> IPFS {
-        let (server, port) = &self.ipfs_data;
-        IPFS::new(server.as_str(), *port)
+    pub fn ledger(&self) -> &dyn RevisionLedger {
+        self.ledger.as_ref()
     }
 
     pub fn load_revision(&mut self, revision_number: u128) -> Result<Revision, QFSError> {
         let tag = self.fetch_revision_tag(revision_number)?;
-        Ok(Revision::new(self.get_ipfs(), tag, self.cache_dir.as_path()))
+        self.attach_cache(Revision::new(self.store(), tag, self.cache_dir.as_path()))
+    }
+
+    pub fn open_revision(&self, tag: &RevisionTag) -> Result<Revision, QFSError> {
+        let tag = RevisionTag::new(tag.hash(), tag.revision());
+        self.attach_cache(Revision::new(self.store(), tag, self.cache_dir.as_path()))
     }
 
     pub fn load_current_revision(&mut self) -> Result<Option<Revision>, QFSError> {
@@ -52,7 +81,13 @@ impl Repository {
         if tag.revision() == 0 {
             return Ok(None);
         }
-        Ok(Some(Revision::new(self.get_ipfs(), tag, self.cache_dir.as_path())))
+        Ok(Some(self.attach_cache(Revision::new(self.store(), tag, self.cache_dir.as_path()))?))
+    }
+
+    /// Publishes a committed root catalog. The ledger refuses it if it has
+    /// moved on from the revision the commit was based on.
+    pub fn push_revision(&mut self, committed: &RevisionTag) -> Result<RevisionTag, QFSError> {
+        self.ledger.add_revision(committed.hash(), committed.revision().saturating_sub(1))
     }
 
     pub fn create_revision(&mut self) -> Result<Revision, QFSError> {
@@ -61,11 +96,81 @@ impl Repository {
             (current_revision_tag.hash().clone(), current_revision_tag.revision())
         };
         match revision {
-            0 => Revision::genesis(self.get_ipfs(), self.cache_dir.as_path()),
+            0 => self.attach_cache(Revision::genesis(self.store(), self.cache_dir.as_path())?),
             _ => {
                 let tag = RevisionTag::new(&hash, revision + 1);
-                Ok(Revision::new(self.get_ipfs(), tag, self.cache_dir.as_path()))
+                self.attach_cache(Revision::new(self.store(), tag, self.cache_dir.as_path()))
             }
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tempfile::tempdir;
+
+    use crate::errors::QFSError;
+    use crate::models::cache::CacheManager;
+    use crate::models::ledger::LocalLedger;
+    use crate::models::repository::{DEFAULT_NAME, Repository};
+    use crate::operations::store::MemoryStore;
+
+    #[test]
+    fn repository_should_work_without_a_chain() {
+        let cache_dir = tempdir().unwrap();
+        let ledger = LocalLedger::in_memory("owner").unwrap();
+        let mut repository = Repository::new(DEFAULT_NAME, Box::new(ledger), Arc::new(MemoryStore::new()), cache_dir.path());
+        assert!(repository.load_current_revision().unwrap().is_none());
+
+        let mut revision = repository.create_revision().unwrap();
+        let tag = revision.publish().unwrap();
+        let pushed = repository.push_revision(&tag).unwrap();
+        assert_eq!(pushed.revision(), 1);
+
+        let current = repository.load_current_revision().unwrap().unwrap();
+        assert_eq!(current.hash(), tag.hash());
+        assert_eq!(repository.load_revision(1).unwrap().hash(), tag.hash());
+        assert!(repository.load_revision(2).is_err());
+    }
+
+    #[test]
+    fn push_revision_should_refuse_outdated_commits() {
+        let cache_dir = tempdir().unwrap();
+        let ledger = LocalLedger::in_memory("owner").unwrap();
+        let mut repository = Repository::new(DEFAULT_NAME, Box::new(ledger), Arc::new(MemoryStore::new()), cache_dir.path());
+        let first = repository.create_revision().unwrap().publish().unwrap();
+        let concurrent = repository.create_revision().unwrap().publish().unwrap();
+        repository.push_revision(&first).unwrap();
+
+        match repository.push_revision(&concurrent) {
+            Err(QFSError::Conflict { base, current }) => assert_eq!((base, current), (0, 1)),
+            result => panic!("Unexpected push result {:?}", result),
+        }
+        assert_eq!(repository.ledger().total_revisions().unwrap(), 1);
+        let next = repository.load_current_revision().unwrap().unwrap().publish().unwrap();
+        assert_eq!(repository.push_revision(&next).unwrap().revision(), 2);
+    }
+
+    #[test]
+    fn repository_cache_should_keep_the_catalogs_of_open_revisions() {
+        let cache_dir = tempdir().unwrap();
+        let database = tempdir().unwrap();
+        let cache = Arc::new(CacheManager::open(cache_dir.path(), database.path().join("cache").as_path(), 0).unwrap());
+        let ledger = LocalLedger::in_memory("owner").unwrap();
+        let mut repository = Repository::new(DEFAULT_NAME, Box::new(ledger), Arc::new(MemoryStore::new()), cache_dir.path())
+            .with_cache(cache.clone());
+        let tag = repository.create_revision().unwrap().publish().unwrap();
+        repository.push_revision(&tag).unwrap();
+
+        let revision = repository.load_current_revision().unwrap().unwrap();
+        revision.retrieve_root_catalog().unwrap();
+        cache.clean().unwrap();
+        assert!(cache_dir.path().join(tag.hash().as_ref()).exists());
+        drop(revision);
+        cache.clean().unwrap();
+        assert!(!cache_dir.path().join(tag.hash().as_ref()).exists());
+    }
+}
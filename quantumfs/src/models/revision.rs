@@ -1,18 +1,26 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 
 use filepath::FilePath;
+use tempfile::NamedTempFile;
 
 use crate::errors::QFSError;
-use crate::models::catalog::Catalog;
-use crate::models::directoryentry::DirectoryEntry;
+use crate::models::cache::CacheManager;
+use crate::models::catalog::{Catalog, CatalogReference};
+use crate::models::chunk::{self, Chunk};
+use crate::models::directoryentry::{DirectoryEntry, flags};
 use crate::operations::{ipfs, path};
-use crate::operations::ipfs::IPFS;
+use crate::operations::store::ObjectStore;
 use crate::types::ipfs::IpfsHash;
 
-#[derive(Debug)]
+/// Directory of the cache where objects failing their hash check are moved.
+pub static QUARANTINE_DIR: &str = "quarantine";
+
+#[derive(Debug, Clone)]
 pub struct RevisionTag {
     hash: IpfsHash,
     revision: u128,
@@ -37,35 +45,63 @@ impl RevisionTag {
 
 #[derive(Debug)]
 pub struct Revision {
-    ipfs: IPFS,
+    store: Arc<dyn ObjectStore>,
     tag: RevisionTag,
-    catalogs: HashMap<IpfsHash, Catalog>,
+    catalogs: RwLock<HashMap<IpfsHash, Arc<Catalog>>>,
+    /// Held per hash while a catalog is being loaded, so it is fetched and
+    /// migrated once without holding up the loading of other catalogs.
+    loading: Mutex<HashMap<IpfsHash, Arc<Mutex<()>>>>,
+    modified: Mutex<HashSet<IpfsHash>>,
+    verified: Mutex<HashSet<IpfsHash>>,
     cache_dir: PathBuf,
+    cache: Option<Arc<CacheManager>>,
 }
 
 impl Revision {
-    pub fn new(ipfs: IPFS, tag: RevisionTag, cache_dir: &Path) -> Self {
+    pub fn new(store: Arc<dyn ObjectStore>, tag: RevisionTag, cache_dir: &Path) -> Self {
         Self {
-            ipfs,
+            store,
             tag,
-            catalogs: HashMap::new(),
+            catalogs: RwLock::new(HashMap::new()),
+            loading: Mutex::new(HashMap::new()),
+            modified: Mutex::new(HashSet::new()),
+            verified: Mutex::new(HashSet::new()),
             cache_dir: cache_dir.to_owned(),
+            cache: None,
         }
     }
 
-    pub fn genesis(ipfs: IPFS, cache_dir: &Path) -> Result<Self, QFSError> {
+    pub fn genesis(store: Arc<dyn ObjectStore>, cache_dir: &Path) -> Result<Self, QFSError> {
         let catalog = Catalog::new(cache_dir)?;
-        let hash = ipfs.add(catalog.file())?;
-        let mut instance = Self {
-            ipfs,
+        let hash = store.put_file(catalog.file())?;
+        let instance = Self {
+            store,
             tag: RevisionTag::new(&hash, 0),
-            catalogs: HashMap::new(),
+            catalogs: RwLock::new(HashMap::new()),
+            loading: Mutex::new(HashMap::new()),
+            modified: Mutex::new(HashSet::new()),
+            verified: Mutex::new(HashSet::new()),
             cache_dir: PathBuf::from(cache_dir),
+            cache: None,
         };
         instance.add_catalog(catalog);
         Ok(instance)
     }
 
+    /// Hands the cached objects over to a cache manager, which will keep the
+    /// catalogs of this revision for as long as it is alive.
+    pub fn set_cache(&mut self, cache: Arc<CacheManager>) -> Result<(), QFSError> {
+        for hash in self.catalogs.read().unwrap().keys() {
+            cache.pin(hash)?;
+        }
+        self.cache = Some(cache);
+        Ok(())
+    }
+
+    pub fn cache(&self) -> Option<&Arc<CacheManager>> {
+        self.cache.as_ref()
+    }
+
     pub fn hash(&self) -> &IpfsHash {
         &self.tag.hash
     }
@@ -74,55 +110,86 @@ impl Revision {
         self.tag.revision
     }
 
-    pub fn lookup(&mut self, path: &str) -> Result<DirectoryEntry, QFSError> {
+    pub fn lookup(&self, path: &str) -> Result<DirectoryEntry, QFSError> {
         let path = path::canonicalize_path(path);
         let path = path.as_str();
         let best_fit = self.retrieve_catalog_for_path(path)?;
         best_fit.find_directory_entry(path)
     }
 
-    pub fn retrieve_root_catalog(&mut self) -> Result<&Catalog, QFSError> {
+    pub fn retrieve_root_catalog(&self) -> Result<Arc<Catalog>, QFSError> {
         let hash = self.hash().clone();
         self.retrieve_catalog(&hash)
     }
 
-    pub fn retrieve_catalog_for_path(&mut self, path: &str) -> Result<&Catalog, QFSError> {
-        let root_catalog_hash = self.hash();
-        let mut hash = root_catalog_hash.clone();
-        loop {
-            match self.retrieve_catalog(&hash)?.find_nested_for_path(path) {
-                Ok(None) => return Ok(self.get_opened_catalog(&hash).unwrap()),
-                Ok(Some(nested_reference)) => hash = nested_reference.hash().clone(),
-                Err(error) => return Err(error)
-            };
+    fn catalog_hash_for_path(&self, path: &str) -> Result<IpfsHash, QFSError> {
+        let mut hash = self.hash().clone();
+        while let Some(nested_reference) = self.retrieve_catalog(&hash)?.find_nested_for_path(path)? {
+            hash = nested_reference.hash().clone();
         }
+        Ok(hash)
+    }
+
+    pub fn retrieve_catalog_for_path(&self, path: &str) -> Result<Arc<Catalog>, QFSError> {
+        let hash = self.catalog_hash_for_path(path)?;
+        self.retrieve_catalog(&hash)
     }
 
-    pub fn list_directory(&mut self, path: &str) -> Result<Vec<DirectoryEntry>, QFSError> {
+    pub fn list_directory(&self, path: &str) -> Result<Vec<DirectoryEntry>, QFSError> {
         let dirent = self.lookup(path)?;
         if dirent.is_directory() {
             let catalog = self.retrieve_catalog_for_path(path)?;
             let entries = catalog.list_directory(path)?;
             return Ok(entries);
         }
-        Err(QFSError::new(format!("{} is not a directory", path).as_str()))
+        Err(QFSError::NotADirectory(path.to_string()))
     }
 
-    pub fn get_file(&mut self, path: &str) -> Result<File, QFSError> {
-        let result = self.lookup(path)?;
-        if !result.is_file() {
-            return Err(QFSError::new(format!("{} is not a file", path).as_str()));
+    /// Lists the chunks making up the file at `path`. Files that were not
+    /// chunked are returned as a single chunk.
+    pub fn file_chunks(&self, path: &str) -> Result<Vec<Chunk>, QFSError> {
+        let dirent = self.lookup(path)?;
+        if dirent.is_directory() {
+            return Err(QFSError::IsADirectory(path.to_string()));
+        }
+        if !dirent.is_file() {
+            return Err(QFSError::NotAFile(path.to_string()));
         }
-        let hash = &result.hash;
-        self.get_object(hash)
+        if !dirent.is_chunked() {
+            return Ok(vec![Chunk { offset: 0, size: dirent.size, hash: dirent.hash }]);
+        }
+        self.retrieve_catalog_for_path(path)?.list_chunks(path)
+    }
+
+    /// Reads up to `size` bytes at `offset` of a file, fetching only the
+    /// chunks covering the requested range.
+    pub fn read_chunks(&self, chunks: &[Chunk], offset: u64, size: usize) -> Result<Vec<u8>, QFSError> {
+        let (offset, size) = (offset as i64, size as i64);
+        let mut data = Vec::with_capacity(size as usize);
+        for chunk in chunks.iter().filter(|chunk| chunk.overlaps(offset, size)) {
+            let start = offset.max(chunk.offset);
+            let end = (offset + size).min(chunk.end());
+            let mut buffer = vec![0; (end - start) as usize];
+            self.get_object(&chunk.hash)?
+                .read_exact_at(&mut buffer, (start - chunk.offset) as u64)?;
+            data.extend_from_slice(&buffer);
+        }
+        Ok(data)
     }
 
     fn fetch_object(&self, hash: &IpfsHash) -> Result<File, QFSError> {
         let cache_path = self.cache_path_for_hash(hash);
-        let mut file = File::create(cache_path.as_path())
-            .expect(format!("Failure creating a new file in {}", cache_path.to_str().unwrap()).as_str());
-        let bytes = self.ipfs.fetch(hash)?;
-        file.write_all(bytes.as_ref())?;
+        let bytes = self.store.get(hash)?;
+        ipfs::verify_bytes(hash, bytes.as_ref())?;
+        let mut tmpfile = NamedTempFile::new_in(self.cache_dir.as_path())?;
+        tmpfile.write_all(bytes.as_ref())?;
+        tmpfile.persist(cache_path.as_path())
+            .map_err(|error| QFSError::from(error.error))?;
+        self.verified.lock().unwrap().insert(hash.clone());
+        let file = File::open(cache_path.as_path())?;
+        if let Some(ref cache) = self.cache {
+            cache.add(hash, bytes.len() as u64)?;
+        }
         Ok(file)
     }
 
@@ -130,18 +197,49 @@ impl Revision {
         self.cache_dir.join(hash.as_ref())
     }
 
-    fn get_object_from_cache(&self, hash: &IpfsHash) -> Option<File> {
+    /// Opens a cached object, checking its hash the first time it is opened.
+    /// Corrupt objects are moved to the quarantine directory and treated as
+    /// missing, so they get fetched again.
+    fn get_object_from_cache(&self, hash: &IpfsHash) -> Result<Option<File>, QFSError> {
         let path = self.cache_path_for_hash(hash);
-        match File::open(path) {
-            Err(_) => None,
-            Ok(file) => Some(file)
+        let mut file = match File::open(path.as_path()) {
+            Err(_) => return Ok(None),
+            Ok(file) => file
+        };
+        if self.verified.lock().unwrap().contains(hash) {
+            return Ok(Some(file));
         }
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        match ipfs::verify_bytes(hash, bytes.as_ref()) {
+            Ok(()) => {
+                self.verified.lock().unwrap().insert(hash.clone());
+                Ok(Some(File::open(path.as_path())?))
+            }
+            Err(QFSError::Integrity { .. }) => {
+                self.quarantine(path.as_path(), hash)?;
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    fn quarantine(&self, path: &Path, hash: &IpfsHash) -> Result<(), QFSError> {
+        let quarantine_dir = self.cache_dir.join(QUARANTINE_DIR);
+        fs::create_dir_all(quarantine_dir.as_path())?;
+        fs::rename(path, quarantine_dir.join(hash.as_ref()))?;
+        Ok(())
     }
 
-    pub fn get_object(&mut self, hash: &IpfsHash) -> Result<File, QFSError> {
-        match self.get_object_from_cache(hash) {
+    pub fn get_object(&self, hash: &IpfsHash) -> Result<File, QFSError> {
+        match self.get_object_from_cache(hash)? {
             None => self.fetch_object(hash),
-            Some(file) => Ok(file)
+            Some(file) => {
+                if let Some(ref cache) = self.cache {
+                    cache.touch(hash, file.metadata()?.len())?;
+                }
+                Ok(file)
+            }
         }
     }
 
@@ -155,29 +253,608 @@ impl Revision {
         self.retrieve_catalog_for_path(path)?.add_directory_entry(&dirent)
     }
 
-    pub fn retrieve_and_open_catalog(&mut self, hash: &IpfsHash) -> Result<&Catalog, QFSError> {
+    pub fn add_object(&self, file: &File) -> Result<IpfsHash, QFSError> {
+        self.store.put_file(file)
+    }
+
+    /// Uploads a file, splitting it in chunks of `chunk_size` bytes when it
+    /// is bigger than that. Returns the hash to record in its entry along
+    /// with its chunks, which are empty when the file was not split.
+    pub fn add_chunked_object(&self, file: &File, chunk_size: u64) -> Result<(IpfsHash, Vec<Chunk>), QFSError> {
+        let size = file.metadata()?.len();
+        if size <= chunk_size {
+            return Ok((self.add_object(file)?, Vec::new()));
+        }
+        let mut chunks = Vec::new();
+        let mut buffer = Vec::with_capacity(chunk_size as usize);
+        let mut offset = 0;
+        while offset < size {
+            buffer.clear();
+            file.try_clone()?.take(chunk_size).read_to_end(&mut buffer)?;
+            if buffer.is_empty() {
+                break;
+            }
+            let hash = self.store.put(&buffer)?;
+            chunks.push(Chunk { offset: offset as i64, size: buffer.len() as i64, hash });
+            offset += buffer.len() as u64;
+        }
+        Ok((chunk::chunk_list_hash(&chunks)?, chunks))
+    }
+
+    pub fn update_chunks(&mut self, path: &str, chunks: &[Chunk]) -> Result<(), QFSError> {
+        let hash = self.catalog_hash_for_path(path)?;
+        self.retrieve_catalog(&hash)?.update_chunks(path, chunks)?;
+        self.modified.lock().unwrap().insert(hash);
+        Ok(())
+    }
+
+    pub fn update_directory_entry(&mut self, path: &str, dirent: &DirectoryEntry) -> Result<(), QFSError> {
+        let path = path::canonicalize_path(path);
+        let path = path.as_str();
+        let hash = self.catalog_hash_for_path(path)?;
+        self.retrieve_catalog(&hash)?.update_directory_entry(dirent)?;
+        self.modified.lock().unwrap().insert(hash);
+        if dirent.is_nested_catalog_root() && path != "/" {
+            // the parent catalog keeps its own copy of the mountpoint
+            let parent_hash = self.catalog_hash_for_path(path::parent_path(path))?;
+            self.retrieve_catalog(&parent_hash)?.update_directory_entry(dirent)?;
+            self.modified.lock().unwrap().insert(parent_hash);
+        }
+        Ok(())
+    }
+
+    /// Removes an entry together with everything below it, including the
+    /// nested catalogs mounted there.
+    pub fn remove_directory_entry(&mut self, path: &str) -> Result<(), QFSError> {
+        let path = path::canonicalize_path(path);
+        let path = path.as_str();
+        let dirent = self.lookup(path)?;
+        if dirent.is_directory() {
+            self.remove_children(path)?;
+        }
+        if dirent.is_nested_catalog_root() {
+            let nested_hash = self.catalog_hash_for_path(path)?;
+            self.modified.lock().unwrap().remove(&nested_hash);
+            self.remove_catalog(&nested_hash);
+        }
+        let hash = self.catalog_hash_for_path(path::parent_path(path))?;
+        let catalog = self.retrieve_catalog(&hash)?;
+        catalog.remove_directory_entry(path)?;
+        if dirent.is_nested_catalog_root() {
+            catalog.remove_nested(path)?;
+        }
+        self.modified.lock().unwrap().insert(hash);
+        Ok(())
+    }
+
+    pub fn remove_children(&mut self, path: &str) -> Result<(), QFSError> {
+        for child in self.list_directory(path)? {
+            self.remove_directory_entry(path::join_path(path, child.name.as_str()).as_str())?;
+        }
+        Ok(())
+    }
+
+    /// Turns the directory at `path` into the root of a new nested catalog,
+    /// moving its whole subtree out of the catalog that currently holds it.
+    pub fn create_nested_catalog(&mut self, path: &str) -> Result<IpfsHash, QFSError> {
+        let path = path::canonicalize_path(path);
+        let path = path.as_str();
+        let mut root_folder = self.lookup(path)?;
+        if !root_folder.is_directory() {
+            return Err(QFSError::NotADirectory(path.to_string()));
+        }
+        if root_folder.is_nested_catalog_root() {
+            return Err(QFSError::new(format!("{} is already a nested catalog", path).as_str()));
+        }
+        root_folder.flags |= flags::NESTED_CATALOG_ROOT;
+        let parent_hash = self.catalog_hash_for_path(path)?;
+        let catalog = Catalog::new_nested(self.cache_dir.as_path(), &root_folder)?;
+        {
+            let parent = self.retrieve_catalog(&parent_hash)?;
+            let mut pending = vec![path.to_string()];
+            while let Some(directory) = pending.pop() {
+                for child in parent.list_directory(directory.as_str())? {
+                    let child_path = path::join_path(directory.as_str(), child.name.as_str());
+                    if child.is_directory() && !child.is_nested_catalog_root() {
+                        pending.push(child_path.clone());
+                    }
+                    catalog.add_directory_entry(&child)?;
+                    if child.is_chunked() {
+                        catalog.update_chunks(child_path.as_str(), &parent.list_chunks(child_path.as_str())?)?;
+                    }
+                    parent.remove_directory_entry(child_path.as_str())?;
+                }
+            }
+            for nested in parent.list_nested()? {
+                if nested.path().starts_with(path) && path::is_sanitized(nested.path(), path) {
+                    catalog.update_nested(&nested)?;
+                    parent.remove_nested(nested.path())?;
+                }
+            }
+            let size = catalog.file().metadata()?.len() as i64;
+            parent.update_directory_entry(&root_folder)?;
+            parent.update_nested(&CatalogReference::new(path, catalog.hash(), size))?;
+        }
+        let hash = catalog.hash().clone();
+        self.add_catalog(catalog);
+        let mut modified = self.modified.lock().unwrap();
+        modified.insert(parent_hash);
+        modified.insert(hash.clone());
+        Ok(hash)
+    }
+
+    fn catalog_mountpoints(&self) -> Result<HashMap<IpfsHash, String>, QFSError> {
+        let mut mountpoints = HashMap::new();
+        let mut pending = vec![(self.hash().clone(), String::from("/"))];
+        while let Some((hash, mountpoint)) = pending.pop() {
+            for nested in self.retrieve_catalog(&hash)?.list_nested()? {
+                pending.push((nested.hash().clone(), nested.path().to_string()));
+            }
+            mountpoints.insert(hash, mountpoint);
+        }
+        Ok(mountpoints)
+    }
+
+    /// Highest hardlink group used in any catalog of the revision.
+    pub fn max_hardlink_group(&self) -> Result<i64, QFSError> {
+        let mut max = 0;
+        for hash in self.catalog_mountpoints()?.keys() {
+            for dirent in self.retrieve_catalog(hash)?.list_hardlinks()? {
+                max = max.max(dirent.hardlink_group);
+            }
+        }
+        Ok(max)
+    }
+
+    /// Updates the link counts of every hardlink group after some of their
+    /// members were added, removed or replaced, wherever they are in the
+    /// revision. Groups left with a single member are dissolved.
+    pub fn recount_hardlinks(&mut self) -> Result<(), QFSError> {
+        let mut members = Vec::new();
+        let mut counts: HashMap<i64, i64> = HashMap::new();
+        for hash in self.catalog_mountpoints()?.keys() {
+            for dirent in self.retrieve_catalog(hash)?.list_hardlinks()? {
+                *counts.entry(dirent.hardlink_group).or_insert(0) += 1;
+                members.push((hash.clone(), dirent));
+            }
+        }
+        for (hash, mut dirent) in members {
+            let links = counts[&dirent.hardlink_group];
+            if links != dirent.links {
+                if links == 1 {
+                    dirent.hardlink_group = 0;
+                }
+                dirent.links = links;
+                self.retrieve_catalog(&hash)?.update_directory_entry(&dirent)?;
+                self.modified.lock().unwrap().insert(hash);
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts the entries below every directory of a catalog, without
+    /// descending into its nested catalogs.
+    fn subtree_sizes(catalog: &Catalog, path: &str, sizes: &mut Vec<(String, i64)>) -> Result<i64, QFSError> {
+        let mut total = 0;
+        for child in catalog.list_directory(path)? {
+            total += 1;
+            if child.is_directory() && !child.is_nested_catalog_root() {
+                let child_path = path::join_path(path, child.name.as_str());
+                let size = Self::subtree_sizes(catalog, child_path.as_str(), sizes)?;
+                sizes.push((child_path, size));
+                total += size;
+            }
+        }
+        Ok(total)
+    }
+
+    /// Splits every modified catalog holding more than `max_entries` entries,
+    /// nesting its biggest directories first.
+    pub fn split_catalogs(&mut self, max_entries: i64) -> Result<(), QFSError> {
+        let mountpoints = self.catalog_mountpoints()?;
+        let mut pending: Vec<(IpfsHash, String)> = mountpoints.into_iter()
+            .filter(|(hash, _)| self.modified.lock().unwrap().contains(hash))
+            .collect();
+        while let Some((hash, mountpoint)) = pending.pop() {
+            let catalog = self.retrieve_catalog(&hash)?;
+            if catalog.count_entries()? <= max_entries {
+                continue;
+            }
+            let mut sizes = Vec::new();
+            Self::subtree_sizes(&catalog, mountpoint.as_str(), &mut sizes)?;
+            let biggest = sizes.into_iter().max_by_key(|(_, size)| *size);
+            if let Some((directory, size)) = biggest {
+                if size > 0 {
+                    let nested_hash = self.create_nested_catalog(directory.as_str())?;
+                    pending.push((nested_hash, directory));
+                    pending.push((hash, mountpoint));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn publish_catalog(&mut self, hash: &IpfsHash) -> Result<(IpfsHash, i64), QFSError> {
+        for nested in self.retrieve_catalog(hash)?.list_nested()? {
+            let (nested_hash, size) = self.publish_catalog(nested.hash())?;
+            if &nested_hash != nested.hash() || size != nested.size() {
+                let reference = CatalogReference::new(nested.path(), &nested_hash, size);
+                self.retrieve_catalog(hash)?.update_nested(&reference)?;
+                self.modified.lock().unwrap().insert(hash.clone());
+            }
+        }
+        let catalog = self.retrieve_catalog(hash)?;
+        let path = catalog.database_path().to_owned();
+        let size = fs::metadata(path.as_path())?.len() as i64;
+        if !self.modified.lock().unwrap().remove(hash) {
+            return Ok((hash.clone(), size));
+        }
+        // close the database before uploading it
+        catalog.keep_upgrade()?;
+        drop(catalog);
+        self.remove_catalog(hash);
+        let new_hash = self.add_object(&File::open(path.as_path())?)?;
+        fs::rename(path.as_path(), self.cache_path_for_hash(&new_hash))?;
+        if let Some(ref cache) = self.cache {
+            cache.add(&new_hash, size as u64)?;
+        }
+        Ok((new_hash, size))
+    }
+
+    /// Uploads every modified catalog, children first so that each parent
+    /// references the new hashes of its nested catalogs, and returns the tag
+    /// of the resulting revision.
+    pub fn publish(&mut self) -> Result<RevisionTag, QFSError> {
+        let root_hash = self.hash().clone();
+        let (hash, _) = self.publish_catalog(&root_hash)?;
+        self.tag = RevisionTag::new(&hash, self.revision() + 1);
+        Ok(self.tag.clone())
+    }
+
+    fn loading_lock(&self, hash: &IpfsHash) -> Arc<Mutex<()>> {
+        self.loading.lock().unwrap().entry(hash.clone()).or_default().clone()
+    }
+
+    pub fn retrieve_and_open_catalog(&self, hash: &IpfsHash) -> Result<Arc<Catalog>, QFSError> {
+        let lock = self.loading_lock(hash);
+        let _loading = lock.lock().unwrap();
+        // another thread may have loaded it while this one was waiting
+        if let Some(catalog) = self.get_opened_catalog(hash) {
+            return Ok(catalog);
+        }
+        let result = self.load_catalog(hash);
+        // later callers find the catalog opened, or try again after a failure
+        self.loading.lock().unwrap().remove(hash);
+        result
+    }
+
+    fn load_catalog(&self, hash: &IpfsHash) -> Result<Arc<Catalog>, QFSError> {
         let catalog_file = self.get_object(hash)?;
         let catalog_file_path = catalog_file.path()?;
         let catalog = Catalog::load(catalog_file_path.as_path())?;
-        self.add_catalog(catalog);
-        Ok(&self.catalogs[&hash])
+        if catalog.upgraded() {
+            // publish the upgraded copy along with the next revision
+            self.modified.lock().unwrap().insert(hash.clone());
+        }
+        Ok(self.add_catalog(catalog))
+    }
+
+    pub fn retrieve_catalog(&self, hash: &IpfsHash) -> Result<Arc<Catalog>, QFSError> {
+        match self.get_opened_catalog(hash) {
+            Some(catalog) => Ok(catalog),
+            None => self.retrieve_and_open_catalog(hash),
+        }
+    }
+
+    pub fn get_opened_catalog(&self, hash: &IpfsHash) -> Option<Arc<Catalog>> {
+        self.catalogs.read().unwrap().get(hash).cloned()
+    }
+
+    fn remove_catalog(&mut self, hash: &IpfsHash) {
+        if self.catalogs.get_mut().unwrap().remove(hash).is_some() {
+            if let Some(ref cache) = self.cache {
+                cache.unpin(hash).ok();
+            }
+        }
+    }
+
+    pub fn add_catalog(&self, catalog: Catalog) -> Arc<Catalog> {
+        let hash = catalog.hash().clone();
+        let catalog = Arc::new(catalog);
+        if self.catalogs.write().unwrap().insert(hash.clone(), catalog.clone()).is_none() {
+            if let Some(ref cache) = self.cache {
+                cache.pin(&hash).ok();
+            }
+        }
+        catalog
+    }
+}
+
+impl Drop for Revision {
+    fn drop(&mut self) {
+        if let Some(ref cache) = self.cache {
+            for hash in self.catalogs.get_mut().unwrap().keys() {
+                cache.unpin(hash).ok();
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+
+    use filepath::FilePath;
+    use rusqlite::Connection;
+    use tempfile::tempdir;
+
+    use crate::errors::QFSError;
+    use crate::models::cache::CacheManager;
+    use crate::models::catalog::Catalog;
+    use crate::models::directoryentry::{DirectoryEntry, flags};
+    use crate::models::revision::{QUARANTINE_DIR, Revision, RevisionTag};
+    use crate::operations::{database, ipfs};
+    use crate::operations::path;
+    use crate::operations::store::{DirectoryStore, MemoryStore, ObjectStore};
+    use crate::operations::xattr::Xattrs;
+    use crate::types::ipfs::IpfsHash;
+
+    fn hash(content: &str) -> IpfsHash {
+        IpfsHash::new(ipfs::hash_bytes(content.as_bytes()).as_str()).unwrap()
+    }
+
+    fn create_revision(cache_dir: &Path) -> Revision {
+        let catalog = Catalog::new(cache_dir).unwrap();
+        let tag = RevisionTag::new(catalog.hash(), 1);
+        Revision::new(Arc::new(MemoryStore::new()), tag, cache_dir)
     }
 
-    pub fn retrieve_catalog(&mut self, hash: &IpfsHash) -> Result<&Catalog, QFSError> {
-        if self.catalogs.contains_key(&hash) {
-            return Ok(&self.catalogs[&hash]);
+    fn add_entry(revision: &mut Revision, entry_path: &str, entry_flags: i64) {
+        let dirent = DirectoryEntry {
+            path: hash(entry_path),
+            parent: hash(path::parent_path(entry_path)),
+            hash: hash(entry_path),
+            flags: entry_flags,
+            size: 0,
+            mode: 0,
+            mtime: 0,
+            name: entry_path.rsplit('/').next().unwrap().to_string(),
+            symlink: "".to_string(),
+            uid: Some(0),
+            gid: Some(0),
+            xattrs: Xattrs::new(),
+            hardlink_group: 0,
+            links: 1,
+        };
+        revision.update_directory_entry(entry_path, &dirent).unwrap();
+    }
+
+    #[test]
+    fn create_nested_catalog_should_move_the_subtree() {
+        let cache_dir = tempdir().unwrap();
+        let mut revision = create_revision(cache_dir.path());
+        add_entry(&mut revision, "/dir", flags::DIRECTORY);
+        add_entry(&mut revision, "/dir/subdir", flags::DIRECTORY);
+        add_entry(&mut revision, "/dir/subdir/file1", flags::FILE);
+        add_entry(&mut revision, "/file2", flags::FILE);
+
+        let nested_hash = revision.create_nested_catalog("/dir").unwrap();
+        let root_hash = revision.hash().clone();
+        let root = revision.retrieve_catalog(&root_hash).unwrap();
+        assert_eq!(root.count_entries().unwrap(), 3);
+        let nested = root.list_nested().unwrap();
+        assert_eq!(nested.len(), 1);
+        assert_eq!(nested[0].path(), "/dir");
+        assert_eq!(nested[0].hash(), &nested_hash);
+
+        assert_eq!(revision.retrieve_catalog_for_path("/dir/subdir").unwrap().hash(), &nested_hash);
+        assert!(revision.lookup("/dir").unwrap().is_nested_catalog_root());
+        assert!(revision.lookup("/dir/subdir/file1").unwrap().is_file());
+        assert_eq!(revision.list_directory("/dir").unwrap().len(), 1);
+        assert!(revision.create_nested_catalog("/dir").is_err());
+    }
+
+    #[test]
+    fn lookup_should_be_shared_between_threads() {
+        let cache_dir = tempdir().unwrap();
+        let mut revision = create_revision(cache_dir.path());
+        add_entry(&mut revision, "/dir", flags::DIRECTORY);
+        for index in 0..10 {
+            add_entry(&mut revision, format!("/dir/file{}", index).as_str(), flags::FILE);
+        }
+        revision.create_nested_catalog("/dir").unwrap();
+        let tag = revision.publish().unwrap();
+        let published_dir = tempdir().unwrap();
+        let published = Arc::new(Revision::new(revision.store.clone(), tag, published_dir.path()));
+
+        let threads: Vec<_> = (0..8).map(|_| {
+            let published = published.clone();
+            thread::spawn(move || {
+                for index in 0..10 {
+                    let path = format!("/dir/file{}", index);
+                    assert!(published.lookup(path.as_str()).unwrap().is_file());
+                }
+                assert_eq!(published.list_directory("/dir").unwrap().len(), 10);
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(published.catalogs.read().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn loading_a_catalog_should_not_wait_for_other_catalogs() {
+        let cache_dir = tempdir().unwrap();
+        let mut revision = create_revision(cache_dir.path());
+        add_entry(&mut revision, "/file", flags::FILE);
+        let tag = revision.publish().unwrap();
+        let published_dir = tempdir().unwrap();
+        let published = Revision::new(revision.store.clone(), tag, published_dir.path());
+
+        let other = published.loading_lock(&hash("other"));
+        let _loading = other.lock().unwrap();
+        assert!(published.lookup("/file").unwrap().is_file());
+        assert!(!published.loading.lock().unwrap().contains_key(published.hash()));
+    }
+
+    #[test]
+    fn file_chunks_should_tell_directories_from_other_entries() {
+        let cache_dir = tempdir().unwrap();
+        let mut revision = create_revision(cache_dir.path());
+        add_entry(&mut revision, "/dir", flags::DIRECTORY);
+        add_entry(&mut revision, "/link", flags::LINK);
+        add_entry(&mut revision, "/file", flags::FILE);
+        match revision.file_chunks("/dir") {
+            Err(QFSError::IsADirectory(path)) => assert_eq!(path, "/dir"),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        match revision.file_chunks("/link") {
+            Err(QFSError::NotAFile(path)) => assert_eq!(path, "/link"),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert_eq!(revision.file_chunks("/file").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn split_catalogs_should_nest_big_directories() {
+        let cache_dir = tempdir().unwrap();
+        let mut revision = create_revision(cache_dir.path());
+        add_entry(&mut revision, "/small", flags::DIRECTORY);
+        add_entry(&mut revision, "/big", flags::DIRECTORY);
+        add_entry(&mut revision, "/big/inner", flags::DIRECTORY);
+        for index in 0..5 {
+            add_entry(&mut revision, format!("/big/file{}", index).as_str(), flags::FILE);
+            add_entry(&mut revision, format!("/big/inner/file{}", index).as_str(), flags::FILE);
+        }
+
+        revision.split_catalogs(8).unwrap();
+        let root_hash = revision.hash().clone();
+        let nested = revision.retrieve_catalog(&root_hash).unwrap().list_nested().unwrap();
+        assert_eq!(nested.len(), 1);
+        assert_eq!(nested[0].path(), "/big");
+        let big_hash = nested[0].hash().clone();
+        let nested = revision.retrieve_catalog(&big_hash).unwrap().list_nested().unwrap();
+        assert_eq!(nested.len(), 1);
+        assert_eq!(nested[0].path(), "/big/inner");
+        assert_eq!(revision.list_directory("/big/inner").unwrap().len(), 5);
+    }
+
+    #[test]
+    fn remove_directory_entry_should_drop_nested_catalogs() {
+        let cache_dir = tempdir().unwrap();
+        let mut revision = create_revision(cache_dir.path());
+        add_entry(&mut revision, "/dir", flags::DIRECTORY);
+        add_entry(&mut revision, "/dir/file1", flags::FILE);
+        revision.create_nested_catalog("/dir").unwrap();
+
+        revision.remove_directory_entry("/dir").unwrap();
+        let root_hash = revision.hash().clone();
+        let root = revision.retrieve_catalog(&root_hash).unwrap();
+        assert!(root.list_nested().unwrap().is_empty());
+        assert_eq!(root.count_entries().unwrap(), 1);
+        assert!(revision.lookup("/dir").is_err());
+    }
+
+    #[test]
+    fn get_object_should_quarantine_corrupt_cache_entries() {
+        let cache_dir = tempdir().unwrap();
+        let store = Arc::new(MemoryStore::new());
+        let object = store.put(b"hello world").unwrap();
+        let catalog = Catalog::new(cache_dir.path()).unwrap();
+        let tag = RevisionTag::new(catalog.hash(), 1);
+        let revision = Revision::new(store, tag, cache_dir.path());
+
+        fs::write(cache_dir.path().join(object.as_ref()), "hello censored world").unwrap();
+        let mut content = String::new();
+        revision.get_object(&object).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello world");
+        let quarantined = cache_dir.path().join(QUARANTINE_DIR).join(object.as_ref());
+        assert_eq!(fs::read_to_string(quarantined).unwrap(), "hello censored world");
+    }
+
+    #[test]
+    fn get_object_should_reject_tampered_objects() {
+        let cache_dir = tempdir().unwrap();
+        let store_dir = tempdir().unwrap();
+        let store = Arc::new(DirectoryStore::new(store_dir.path()).unwrap());
+        let object = store.put(b"hello world").unwrap();
+        fs::write(store_dir.path().join(object.as_ref()), "hello censored world").unwrap();
+        let catalog = Catalog::new(cache_dir.path()).unwrap();
+        let tag = RevisionTag::new(catalog.hash(), 1);
+        let revision = Revision::new(store, tag, cache_dir.path());
+
+        match revision.get_object(&object) {
+            Err(QFSError::Integrity { expected, .. }) => assert_eq!(expected, object),
+            result => panic!("Unexpected result {:?}", result),
         }
-        self.retrieve_and_open_catalog(hash)
+        assert!(!cache_dir.path().join(object.as_ref()).exists());
     }
 
-    pub fn get_opened_catalog(&self, hash: &IpfsHash) -> Option<&Catalog> {
-        self.catalogs.get(hash)
+    #[test]
+    fn retrieve_catalog_should_survive_a_full_cache() {
+        let cache_dir = tempdir().unwrap();
+        let database = tempdir().unwrap();
+        let mut revision = create_revision(cache_dir.path());
+        add_entry(&mut revision, "/file", flags::FILE);
+        let tag = revision.publish().unwrap();
+
+        let published_dir = tempdir().unwrap();
+        let cache = CacheManager::open(published_dir.path(), database.path().join("cache").as_path(), 5).unwrap();
+        let pinned = hash("pinned");
+        fs::write(published_dir.path().join(pinned.as_ref()), "pinned").unwrap();
+        cache.add(&pinned, 6).unwrap();
+        cache.pin(&pinned).unwrap();
+        let mut published = Revision::new(revision.store.clone(), tag, published_dir.path());
+        published.set_cache(Arc::new(cache)).unwrap();
+
+        assert!(published.lookup("/file").unwrap().is_file());
+        assert!(published_dir.path().join(published.hash().as_ref()).exists());
+        assert!(published_dir.path().join(pinned.as_ref()).exists());
     }
 
-    pub fn add_catalog(&mut self, catalog: Catalog) {
-        self.catalogs.insert(
-            catalog.hash().clone(),
-            catalog,
-        );
+    #[test]
+    fn publish_should_add_the_catalogs_to_the_cache() {
+        let cache_dir = tempdir().unwrap();
+        let database = tempdir().unwrap();
+        let cache = Arc::new(CacheManager::open(cache_dir.path(), database.path().join("cache").as_path(), 1 << 30).unwrap());
+        let mut revision = create_revision(cache_dir.path());
+        revision.set_cache(cache.clone()).unwrap();
+        add_entry(&mut revision, "/file", flags::FILE);
+        let before = cache.stats().unwrap();
+        let tag = revision.publish().unwrap();
+
+        let size = fs::metadata(cache_dir.path().join(tag.hash().as_ref())).unwrap().len() as i64;
+        let after = cache.stats().unwrap();
+        assert_eq!((after.objects, after.size), (before.objects + 1, before.size + size));
+    }
+
+    #[test]
+    fn publish_should_upload_upgraded_catalogs() {
+        let cache_dir = tempdir().unwrap();
+        let legacy_dir = tempdir().unwrap();
+        let store = Arc::new(MemoryStore::new());
+        let legacy = Catalog::new(legacy_dir.path()).unwrap().file().path().unwrap();
+        Connection::open(legacy.as_path()).unwrap()
+            .execute_batch("DROP TABLE properties;").unwrap();
+        let legacy = fs::read(legacy.as_path()).unwrap();
+        let hash = store.put(legacy.as_ref()).unwrap();
+        let mut revision = Revision::new(store.clone(), RevisionTag::new(&hash, 1), cache_dir.path());
+
+        assert!(revision.lookup("/").unwrap().is_directory());
+        assert_eq!(fs::read(cache_dir.path().join(hash.as_ref())).unwrap(), legacy);
+        let tag = revision.publish().unwrap();
+        assert_eq!(fs::read(cache_dir.path().join(hash.as_ref())).unwrap(), legacy);
+        assert_ne!(tag.hash(), &hash);
+        let published_dir = tempdir().unwrap();
+        let published = Revision::new(store, tag, published_dir.path());
+        let catalog = published.retrieve_root_catalog().unwrap();
+        assert!(!catalog.upgraded());
+        assert_eq!(catalog.schema_revision().unwrap(), database::SCHEMA_REVISION);
     }
 }
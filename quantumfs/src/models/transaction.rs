@@ -0,0 +1,630 @@
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use filepath::FilePath;
+
+use crate::errors::QFSError;
+use crate::models::chunk::Chunk;
+use crate::models::directoryentry::{DirectoryEntry, flags};
+use crate::models::revision::{Revision, RevisionTag};
+use crate::operations::ipfs;
+use crate::operations::overlay;
+use crate::operations::path;
+use crate::operations::store::ObjectStore;
+use crate::operations::xattr;
+use crate::types::ipfs::IpfsHash;
+
+static LOCK_FILE: &str = "lock";
+static COMMIT_FILE: &str = "commit";
+static CATALOGS_DIR: &str = "catalogs";
+static OVERLAY_DIR: &str = "overlay";
+
+fn hash_path(path: &str) -> Result<IpfsHash, QFSError> {
+    IpfsHash::new(ipfs::hash_bytes(path.as_bytes()).as_str())
+}
+
+/// Hardlinks found in the staged tree, with the entry staged for the first
+/// link of each of them, which the other links copy.
+#[derive(Default)]
+struct StagedLinks {
+    counts: HashMap<(u64, u64), i64>,
+    staged: HashMap<(u64, u64), (DirectoryEntry, Vec<Chunk>)>,
+    last_group: Option<i64>,
+}
+
+impl StagedLinks {
+    /// Assigns a group that no entry of the revision uses yet.
+    fn next_group(&mut self, revision: &Revision) -> Result<i64, QFSError> {
+        let group = match self.last_group {
+            Some(group) => group + 1,
+            None => revision.max_hardlink_group()? + 1,
+        };
+        self.last_group = Some(group);
+        Ok(group)
+    }
+}
+
+#[derive(Debug)]
+pub struct Transaction {
+    tag: RevisionTag,
+    staging_dir: PathBuf,
+}
+
+impl Transaction {
+    fn lock_path(staging_dir: &Path) -> PathBuf {
+        staging_dir.join(LOCK_FILE)
+    }
+
+    fn parse_tag(content: &str) -> Result<RevisionTag, QFSError> {
+        let mut lines = content.lines();
+        let hash = lines.next()
+            .ok_or_else(|| QFSError::new("Malformed transaction record"))?;
+        let revision = lines.next()
+            .and_then(|revision| revision.parse::<u128>().ok())
+            .ok_or_else(|| QFSError::new("Malformed transaction record"))?;
+        Ok(RevisionTag::new(&IpfsHash::new(hash)?, revision))
+    }
+
+    pub fn open(revision: &mut Revision, staging_dir: &Path) -> Result<Self, QFSError> {
+        fs::create_dir_all(staging_dir)?;
+        let mut lock = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(Self::lock_path(staging_dir)) {
+            Ok(file) => file,
+            Err(ref error) if error.kind() == ErrorKind::AlreadyExists =>
+                return Err(QFSError::new("A transaction is already open for this repository")),
+            Err(error) => return Err(QFSError::from(error)),
+        };
+        lock.write_all(format!("{}\n{}\n", revision.hash(), revision.revision()).as_bytes())?;
+        let transaction = Self {
+            tag: RevisionTag::new(revision.hash(), revision.revision()),
+            staging_dir: staging_dir.to_owned(),
+        };
+        if let Err(error) = transaction.checkout(revision) {
+            transaction.clean_up()?;
+            return Err(error);
+        }
+        Ok(transaction)
+    }
+
+    pub fn load(staging_dir: &Path) -> Result<Self, QFSError> {
+        let content = match fs::read_to_string(Self::lock_path(staging_dir)) {
+            Ok(content) => content,
+            Err(ref error) if error.kind() == ErrorKind::NotFound =>
+                return Err(QFSError::new("No transaction is open for this repository")),
+            Err(error) => return Err(QFSError::from(error)),
+        };
+        Ok(Self {
+            tag: Self::parse_tag(content.as_str())?,
+            staging_dir: staging_dir.to_owned(),
+        })
+    }
+
+    fn checkout(&self, revision: &mut Revision) -> Result<(), QFSError> {
+        fs::create_dir_all(self.overlay_dir())?;
+        fs::create_dir_all(self.catalogs_dir())?;
+        let mut pending = vec![revision.hash().clone()];
+        while let Some(hash) = pending.pop() {
+            let catalog = revision.retrieve_catalog(&hash)?;
+            for nested in catalog.list_nested()? {
+                pending.push(nested.hash().clone());
+            }
+            fs::copy(catalog.file().path()?, self.catalog_path(&hash))?;
+        }
+        Ok(())
+    }
+
+    fn stage_entry(path: &str, source: &Path, metadata: &Metadata, revision: &Revision,
+                   chunk_size: u64) -> Result<(DirectoryEntry, Vec<Chunk>), QFSError> {
+        let file_type = metadata.file_type();
+        let mut chunks = Vec::new();
+        let (hash, flags, symlink) = if file_type.is_dir() {
+            (IpfsHash::new(ipfs::hash_bytes(&[]).as_str())?, flags::DIRECTORY, String::new())
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(source)?.to_string_lossy().into_owned();
+            (IpfsHash::new(ipfs::hash_bytes(target.as_bytes()).as_str())?, flags::LINK, target)
+        } else {
+            let (hash, file_chunks) = revision.add_chunked_object(&File::open(source)?, chunk_size)?;
+            chunks = file_chunks;
+            let flags = if chunks.is_empty() { flags::FILE } else { flags::FILE | flags::CHUNKED };
+            (hash, flags, String::new())
+        };
+        let dirent = DirectoryEntry {
+            path: hash_path(path)?,
+            parent: hash_path(path::parent_path(path))?,
+            hash,
+            flags,
+            size: metadata.len() as i64,
+            mode: metadata.mode() as i64,
+            mtime: metadata.mtime(),
+            name: source.file_name().unwrap().to_string_lossy().into_owned(),
+            symlink,
+            uid: Some(metadata.uid() as i64),
+            gid: Some(metadata.gid() as i64),
+            xattrs: xattr::read(source)?,
+            hardlink_group: 0,
+            links: 1,
+        };
+        Ok((dirent, chunks))
+    }
+
+    /// Groups the files of the staged tree that are hardlinks to each other,
+    /// by device and inode, wherever their links are. Links to files outside
+    /// the tree are ignored, so those files are published as copies.
+    fn find_hardlinks(directory: &Path, links: &mut HashMap<(u64, u64), i64>) -> Result<(), QFSError> {
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            if overlay::is_special(entry.file_name().to_string_lossy().as_ref()) {
+                continue;
+            }
+            let metadata = fs::symlink_metadata(entry.path())?;
+            if metadata.is_dir() {
+                Self::find_hardlinks(entry.path().as_path(), links)?;
+            } else if metadata.is_file() && metadata.nlink() > 1 {
+                *links.entry((metadata.dev(), metadata.ino())).or_insert(0) += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn stage_directory(&self, revision: &mut Revision, directory: &Path, path: &str, chunk_size: u64,
+                       links: &mut StagedLinks) -> Result<(), QFSError> {
+        if overlay::is_opaque(directory) {
+            revision.remove_children(path)?;
+        }
+        let mut children = fs::read_dir(directory)?
+            .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<Vec<String>, _>>()?;
+        children.sort();
+        for name in children.iter() {
+            if let Some(target) = overlay::whiteout_target(name.as_str()) {
+                let target_path = path::join_path(path, target);
+                if revision.lookup(target_path.as_str()).is_ok() {
+                    revision.remove_directory_entry(target_path.as_str())?;
+                }
+            }
+        }
+        for name in children.iter().filter(|name| !overlay::is_special(name.as_str())) {
+            let source = directory.join(name);
+            let child_path = path::join_path(path, name.as_str());
+            let metadata = fs::symlink_metadata(source.as_path())?;
+            let inode = (metadata.dev(), metadata.ino());
+            let (mut dirent, chunks) = match (links.counts.get(&inode).cloned(), links.staged.get(&inode).cloned()) {
+                (Some(_), Some((first, chunks))) => {
+                    let mut dirent = first.clone();
+                    dirent.path = hash_path(child_path.as_str())?;
+                    dirent.parent = hash_path(path)?;
+                    dirent.name = name.clone();
+                    (dirent, chunks)
+                }
+                (Some(count), None) => {
+                    let (mut dirent, chunks) = Self::stage_entry(child_path.as_str(), source.as_path(), &metadata,
+                                                                 revision, chunk_size)?;
+                    dirent.hardlink_group = links.next_group(revision)?;
+                    dirent.links = count;
+                    links.staged.insert(inode, (dirent.clone(), chunks.clone()));
+                    (dirent, chunks)
+                }
+                _ => Self::stage_entry(child_path.as_str(), source.as_path(), &metadata, revision, chunk_size)?,
+            };
+            match revision.lookup(child_path.as_str()).ok() {
+                Some(ref existing) if existing.is_directory() && dirent.is_directory() => {
+                    // the children are staged below, only the directory itself is compared
+                    dirent.flags = existing.flags;
+                    if *existing != dirent {
+                        revision.update_directory_entry(child_path.as_str(), &dirent)?;
+                    }
+                }
+                Some(ref existing) if *existing == dirent => {}
+                Some(ref existing) if existing.is_directory() => {
+                    revision.remove_directory_entry(child_path.as_str())?;
+                    revision.update_directory_entry(child_path.as_str(), &dirent)?;
+                    revision.update_chunks(child_path.as_str(), &chunks)?;
+                }
+                _ => {
+                    revision.update_directory_entry(child_path.as_str(), &dirent)?;
+                    revision.update_chunks(child_path.as_str(), &chunks)?;
+                }
+            }
+            if metadata.is_dir() {
+                self.stage_directory(revision, source.as_path(), child_path.as_str(), chunk_size, links)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Uploads the staged changes and their catalogs, returning the tag the
+    /// new root catalog would get once pushed. Catalogs growing beyond
+    /// `max_entries` entries are split into nested catalogs, and files bigger
+    /// than `chunk_size` bytes are stored in chunks. The result is kept in
+    /// the staging area until it is published.
+    pub fn commit(self, store: Arc<dyn ObjectStore>, max_entries: i64, chunk_size: u64) -> Result<RevisionTag, QFSError> {
+        let mut revision = Revision::new(store, self.tag.clone(), self.catalogs_dir().as_path());
+        let mut links = StagedLinks::default();
+        Self::find_hardlinks(self.overlay_dir().as_path(), &mut links.counts)?;
+        links.counts.retain(|_, count| *count > 1);
+        self.stage_directory(&mut revision, self.overlay_dir().as_path(), "/", chunk_size, &mut links)?;
+        revision.recount_hardlinks()?;
+        revision.split_catalogs(max_entries)?;
+        let tag = revision.publish()?;
+        fs::write(
+            self.staging_dir.join(COMMIT_FILE),
+            format!("{}\n{}\n", tag.hash(), tag.revision()),
+        )?;
+        self.clean_up()?;
+        Ok(tag)
+    }
+
+    /// Returns the tag of a commit that has not been pushed yet, if any.
+    pub fn load_commit(staging_dir: &Path) -> Result<Option<RevisionTag>, QFSError> {
+        match fs::read_to_string(staging_dir.join(COMMIT_FILE)) {
+            Ok(content) => Self::parse_tag(content.as_str()).map(Some),
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(QFSError::from(error)),
+        }
+    }
+
+    pub fn discard_commit(staging_dir: &Path) -> Result<(), QFSError> {
+        fs::remove_file(staging_dir.join(COMMIT_FILE))
+            .map_err(QFSError::from)
+    }
+
+    /// Drops the staged changes. A commit waiting to be pushed is kept.
+    pub fn abort(self) -> Result<(), QFSError> {
+        self.clean_up()
+    }
+
+    /// Removes the overlay, the catalog copies and the lock, leaving any
+    /// pending commit in the staging directory.
+    fn clean_up(&self) -> Result<(), QFSError> {
+        for directory in [self.overlay_dir(), self.catalogs_dir()].iter() {
+            match fs::remove_dir_all(directory) {
+                Err(ref error) if error.kind() == ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        fs::remove_file(Self::lock_path(self.staging_dir()))
+            .map_err(QFSError::from)
+    }
+
+    pub fn tag(&self) -> &RevisionTag {
+        &self.tag
+    }
+
+    pub fn staging_dir(&self) -> &Path {
+        self.staging_dir.as_path()
+    }
+
+    pub fn overlay_dir(&self) -> PathBuf {
+        self.staging_dir.join(OVERLAY_DIR)
+    }
+
+    pub fn catalogs_dir(&self) -> PathBuf {
+        self.staging_dir.join(CATALOGS_DIR)
+    }
+
+    pub fn catalog_path(&self, hash: &IpfsHash) -> PathBuf {
+        self.catalogs_dir().join(hash.as_ref())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use tempfile::tempdir;
+
+    use crate::models::catalog::Catalog;
+    use crate::models::chunk::DEFAULT_CHUNK_SIZE;
+    use crate::models::revision::{Revision, RevisionTag};
+    use crate::models::transaction::Transaction;
+    use crate::operations::store::{MemoryStore, ObjectStore};
+
+    fn create_revision(store: &Arc<dyn ObjectStore>, cache_dir: &Path) -> Revision {
+        let catalog = Catalog::new(cache_dir).unwrap();
+        let tag = RevisionTag::new(catalog.hash(), 1);
+        Revision::new(store.clone(), tag, cache_dir)
+    }
+
+    #[test]
+    fn open_transaction_should_copy_the_catalogs() {
+        let cache_dir = tempdir().unwrap();
+        let staging_dir = tempdir().unwrap();
+        let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new());
+        let mut revision = create_revision(&store, cache_dir.path());
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        assert!(transaction.overlay_dir().is_dir());
+        assert!(transaction.catalog_path(revision.hash()).is_file());
+        let loaded = Transaction::load(staging_dir.path()).unwrap();
+        assert_eq!(loaded.tag().hash(), revision.hash());
+        assert_eq!(loaded.tag().revision(), 1);
+    }
+
+    #[test]
+    fn commit_should_add_the_staged_files() {
+        let cache_dir = tempdir().unwrap();
+        let staging_dir = tempdir().unwrap();
+        let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new());
+        let mut revision = create_revision(&store, cache_dir.path());
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        fs::create_dir(transaction.overlay_dir().join("dir")).unwrap();
+        fs::write(transaction.overlay_dir().join("dir/file1"), "this is file1").unwrap();
+        let metadata = fs::metadata(transaction.overlay_dir().join("dir/file1")).unwrap();
+        let tag = transaction.commit(store.clone(), 1000, DEFAULT_CHUNK_SIZE).unwrap();
+        assert_eq!(tag.revision(), 2);
+        assert_ne!(tag.hash(), revision.hash());
+        assert!(Transaction::load(staging_dir.path()).is_err());
+        let pending = Transaction::load_commit(staging_dir.path()).unwrap().unwrap();
+        assert_eq!(pending.hash(), tag.hash());
+
+        let committed = Revision::new(store.clone(), tag, cache_dir.path());
+        let files = committed.list_directory("/dir").unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "file1");
+        assert_eq!(files[0].size, 13);
+        assert_eq!(files[0].uid, Some(metadata.uid() as i64));
+        assert_eq!(files[0].gid, Some(metadata.gid() as i64));
+    }
+
+    #[test]
+    fn commit_should_split_big_catalogs() {
+        let cache_dir = tempdir().unwrap();
+        let staging_dir = tempdir().unwrap();
+        let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new());
+        let mut revision = create_revision(&store, cache_dir.path());
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        fs::create_dir(transaction.overlay_dir().join("dir")).unwrap();
+        for index in 0..5 {
+            let file = transaction.overlay_dir().join(format!("dir/file{}", index));
+            fs::write(file, format!("this is file{}", index)).unwrap();
+        }
+        let tag = transaction.commit(store.clone(), 4, DEFAULT_CHUNK_SIZE).unwrap();
+
+        let committed = Revision::new(store.clone(), tag, cache_dir.path());
+        let root_hash = committed.hash().clone();
+        let nested = committed.retrieve_catalog(&root_hash).unwrap().list_nested().unwrap();
+        assert_eq!(nested.len(), 1);
+        assert_eq!(nested[0].path(), "/dir");
+        assert_eq!(committed.list_directory("/dir").unwrap().len(), 5);
+        assert!(committed.lookup("/dir/file3").unwrap().is_file());
+    }
+
+    #[test]
+    fn commit_should_chunk_big_files() {
+        let cache_dir = tempdir().unwrap();
+        let staging_dir = tempdir().unwrap();
+        let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new());
+        let mut revision = create_revision(&store, cache_dir.path());
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        fs::create_dir(transaction.overlay_dir().join("dir")).unwrap();
+        fs::write(transaction.overlay_dir().join("dir/file1"), "this is file1").unwrap();
+        fs::write(transaction.overlay_dir().join("dir/file2"), "file2").unwrap();
+        let tag = transaction.commit(store.clone(), 2, 5).unwrap();
+
+        let committed = Revision::new(store.clone(), tag, cache_dir.path());
+        assert!(committed.lookup("/dir/file1").unwrap().is_chunked());
+        assert!(!committed.lookup("/dir/file2").unwrap().is_chunked());
+        let chunks = committed.file_chunks("/dir/file1").unwrap();
+        assert_eq!(chunks.iter().map(|chunk| chunk.size).collect::<Vec<i64>>(), vec![5, 5, 3]);
+        assert_eq!(committed.read_chunks(&chunks, 3, 6).unwrap(), b"s is f".to_vec());
+        assert_eq!(committed.read_chunks(&chunks, 10, 100).unwrap(), b"le1".to_vec());
+        assert!(committed.read_chunks(&chunks, 13, 10).unwrap().is_empty());
+        let chunks = committed.file_chunks("/dir/file2").unwrap();
+        assert_eq!(committed.read_chunks(&chunks, 0, 100).unwrap(), b"file2".to_vec());
+    }
+
+    #[test]
+    fn commit_should_publish_extended_attributes() {
+        let cache_dir = tempdir().unwrap();
+        let staging_dir = tempdir().unwrap();
+        let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new());
+        let mut revision = create_revision(&store, cache_dir.path());
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        let file = transaction.overlay_dir().join("file1");
+        fs::write(file.as_path(), "this is file1").unwrap();
+        let c_path = CString::new(file.as_os_str().as_bytes()).unwrap();
+        let (name, value) = (CString::new("user.mime_type").unwrap(), "text/plain");
+        let result = unsafe {
+            libc::lsetxattr(c_path.as_ptr(), name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0)
+        };
+        assert_eq!(result, 0);
+        let tag = transaction.commit(store.clone(), 1000, DEFAULT_CHUNK_SIZE).unwrap();
+
+        let committed = Revision::new(store.clone(), tag, cache_dir.path());
+        let xattrs = committed.lookup("/file1").unwrap().xattrs;
+        assert_eq!(xattrs.len(), 1);
+        assert_eq!(xattrs["user.mime_type"], b"text/plain".to_vec());
+        assert!(committed.lookup("/").unwrap().xattrs.is_empty());
+    }
+
+    #[test]
+    fn commit_should_publish_metadata_changes() {
+        let cache_dir = tempdir().unwrap();
+        let staging_dir = tempdir().unwrap();
+        let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new());
+        let mut revision = create_revision(&store, cache_dir.path());
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        fs::create_dir(transaction.overlay_dir().join("dir")).unwrap();
+        for index in 1..=3 {
+            fs::write(transaction.overlay_dir().join(format!("dir/file{}", index)), "unchanged").unwrap();
+        }
+        let tag = transaction.commit(store.clone(), 2, DEFAULT_CHUNK_SIZE).unwrap();
+
+        let mut revision = Revision::new(store.clone(), tag, cache_dir.path());
+        assert!(revision.lookup("/dir").unwrap().is_nested_catalog_root());
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        let dir = transaction.overlay_dir().join("dir");
+        fs::create_dir(dir.as_path()).unwrap();
+        fs::set_permissions(dir.as_path(), fs::Permissions::from_mode(0o700)).unwrap();
+        let file = dir.join("file1");
+        fs::write(file.as_path(), "unchanged").unwrap();
+        let c_path = CString::new(file.as_os_str().as_bytes()).unwrap();
+        let (name, value) = (CString::new("user.mime_type").unwrap(), "text/plain");
+        let result = unsafe {
+            libc::lsetxattr(c_path.as_ptr(), name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0)
+        };
+        assert_eq!(result, 0);
+        let tag = transaction.commit(store.clone(), 2, DEFAULT_CHUNK_SIZE).unwrap();
+
+        let committed = Revision::new(store.clone(), tag, cache_dir.path());
+        let dir = committed.lookup("/dir").unwrap();
+        assert!(dir.is_nested_catalog_root());
+        assert_eq!(dir.mode & 0o7777, 0o700);
+        let root_hash = committed.hash().clone();
+        let mountpoint = committed.retrieve_catalog(&root_hash).unwrap().find_directory_entry("/dir").unwrap();
+        assert_eq!(mountpoint, dir);
+        assert_eq!(committed.lookup("/dir/file1").unwrap().xattrs["user.mime_type"], b"text/plain".to_vec());
+        assert_eq!(committed.list_directory("/dir").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn commit_should_detect_hardlinks() {
+        let cache_dir = tempdir().unwrap();
+        let staging_dir = tempdir().unwrap();
+        let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new());
+        let mut revision = create_revision(&store, cache_dir.path());
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        let overlay_dir = transaction.overlay_dir();
+        fs::create_dir(overlay_dir.join("dir")).unwrap();
+        fs::create_dir(overlay_dir.join("other")).unwrap();
+        fs::write(overlay_dir.join("dir/file1"), "this is file1").unwrap();
+        fs::hard_link(overlay_dir.join("dir/file1"), overlay_dir.join("dir/file2")).unwrap();
+        fs::hard_link(overlay_dir.join("dir/file1"), overlay_dir.join("other/file1")).unwrap();
+        fs::write(overlay_dir.join("dir/file3"), "this is file1").unwrap();
+        let tag = transaction.commit(store.clone(), 1000, DEFAULT_CHUNK_SIZE).unwrap();
+
+        let mut revision = Revision::new(store.clone(), tag, cache_dir.path());
+        let file1 = revision.lookup("/dir/file1").unwrap();
+        let file2 = revision.lookup("/dir/file2").unwrap();
+        assert!(file1.is_hardlink());
+        assert_eq!(file1.hardlink_group, file2.hardlink_group);
+        assert_eq!((file1.links, file2.links), (3, 3));
+        assert_eq!(file1.hash, file2.hash);
+        assert!(!revision.lookup("/dir/file3").unwrap().is_hardlink());
+        assert_eq!(revision.lookup("/other/file1").unwrap().hardlink_group, file1.hardlink_group);
+
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        fs::create_dir(transaction.overlay_dir().join("dir")).unwrap();
+        fs::create_dir(transaction.overlay_dir().join("other")).unwrap();
+        fs::write(transaction.overlay_dir().join("dir/.wh.file2"), "").unwrap();
+        fs::write(transaction.overlay_dir().join("other/.wh.file1"), "").unwrap();
+        let tag = transaction.commit(store.clone(), 1000, DEFAULT_CHUNK_SIZE).unwrap();
+
+        let revision = Revision::new(store.clone(), tag, cache_dir.path());
+        let file1 = revision.lookup("/dir/file1").unwrap();
+        assert!(!file1.is_hardlink());
+        assert_eq!(file1.links, 1);
+    }
+
+    #[test]
+    fn commit_should_group_hardlinks_across_directories() {
+        let cache_dir = tempdir().unwrap();
+        let staging_dir = tempdir().unwrap();
+        let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new());
+        let mut revision = create_revision(&store, cache_dir.path());
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        let overlay_dir = transaction.overlay_dir();
+        fs::create_dir_all(overlay_dir.join("first/inner")).unwrap();
+        fs::create_dir(overlay_dir.join("second")).unwrap();
+        fs::write(overlay_dir.join("first/inner/file"), "shared").unwrap();
+        fs::hard_link(overlay_dir.join("first/inner/file"), overlay_dir.join("second/link")).unwrap();
+        fs::write(overlay_dir.join("first/other"), "other").unwrap();
+        fs::hard_link(overlay_dir.join("first/other"), overlay_dir.join("second/other")).unwrap();
+        // few entries per catalog, so the links end up in different catalogs
+        let tag = transaction.commit(store.clone(), 3, DEFAULT_CHUNK_SIZE).unwrap();
+
+        let mut revision = Revision::new(store.clone(), tag, cache_dir.path());
+        let file = revision.lookup("/first/inner/file").unwrap();
+        let link = revision.lookup("/second/link").unwrap();
+        assert!(file.is_hardlink());
+        assert_eq!(file.hardlink_group, link.hardlink_group);
+        assert_eq!((file.links, link.links), (2, 2));
+        assert_eq!(file.inode(), link.inode());
+        assert_ne!(revision.retrieve_catalog_for_path("/first/inner/file").unwrap().hash(),
+                   revision.retrieve_catalog_for_path("/second/link").unwrap().hash());
+        let other = revision.lookup("/first/other").unwrap();
+        assert_ne!(other.hardlink_group, file.hardlink_group);
+        assert_eq!(other.inode(), revision.lookup("/second/other").unwrap().inode());
+
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        fs::create_dir(transaction.overlay_dir().join("second")).unwrap();
+        fs::write(transaction.overlay_dir().join("second/.wh.link"), "").unwrap();
+        fs::write(transaction.overlay_dir().join("second/new"), "new").unwrap();
+        fs::hard_link(transaction.overlay_dir().join("second/new"), transaction.overlay_dir().join("new")).unwrap();
+        let tag = transaction.commit(store.clone(), 3, DEFAULT_CHUNK_SIZE).unwrap();
+
+        let revision = Revision::new(store.clone(), tag, cache_dir.path());
+        let file = revision.lookup("/first/inner/file").unwrap();
+        assert!(!file.is_hardlink());
+        assert_eq!(file.links, 1);
+        let new = revision.lookup("/new").unwrap();
+        assert!(new.is_hardlink());
+        assert_ne!(new.hardlink_group, other.hardlink_group);
+        assert_eq!(revision.lookup("/first/other").unwrap().links, 2);
+    }
+
+    #[test]
+    fn commit_should_apply_whiteouts() {
+        let cache_dir = tempdir().unwrap();
+        let staging_dir = tempdir().unwrap();
+        let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new());
+        let mut revision = create_revision(&store, cache_dir.path());
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        fs::create_dir(transaction.overlay_dir().join("dir")).unwrap();
+        fs::write(transaction.overlay_dir().join("dir/file1"), "this is file1").unwrap();
+        fs::write(transaction.overlay_dir().join("file2"), "this is file2").unwrap();
+        let tag = transaction.commit(store.clone(), 1000, DEFAULT_CHUNK_SIZE).unwrap();
+
+        let mut revision = Revision::new(store.clone(), tag, cache_dir.path());
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        fs::write(transaction.overlay_dir().join(".wh.dir"), "").unwrap();
+        let tag = transaction.commit(store.clone(), 1000, DEFAULT_CHUNK_SIZE).unwrap();
+
+        let committed = Revision::new(store.clone(), tag, cache_dir.path());
+        let files = committed.list_directory("/").unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "file2");
+        assert!(committed.lookup("/dir/file1").is_err());
+    }
+
+    #[test]
+    fn open_second_transaction_should_fail() {
+        let cache_dir = tempdir().unwrap();
+        let staging_dir = tempdir().unwrap();
+        let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new());
+        let mut revision = create_revision(&store, cache_dir.path());
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        assert!(Transaction::open(&mut revision, staging_dir.path()).is_err());
+        transaction.abort().unwrap();
+        assert!(Transaction::load(staging_dir.path()).is_err());
+        assert!(Transaction::open(&mut revision, staging_dir.path()).is_ok());
+    }
+
+    #[test]
+    fn abort_should_keep_the_pending_commit() {
+        let cache_dir = tempdir().unwrap();
+        let staging_dir = tempdir().unwrap();
+        let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new());
+        let mut revision = create_revision(&store, cache_dir.path());
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        fs::write(transaction.overlay_dir().join("file1"), "this is file1").unwrap();
+        let tag = transaction.commit(store.clone(), 1000, DEFAULT_CHUNK_SIZE).unwrap();
+
+        let transaction = Transaction::open(&mut revision, staging_dir.path()).unwrap();
+        let overlay_dir = transaction.overlay_dir();
+        transaction.abort().unwrap();
+        assert!(!overlay_dir.exists());
+        assert_eq!(Transaction::load_commit(staging_dir.path()).unwrap().unwrap().hash(), tag.hash());
+    }
+}
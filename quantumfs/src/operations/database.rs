@@ -1,10 +1,10 @@
-use std::error::Error;
-
 use rusqlite::{Connection, NO_PARAMS, ToSql};
 
 use crate::errors::QFSError;
 use crate::models::catalog::CatalogReference;
+use crate::models::chunk::{self, Chunk};
 use crate::models::directoryentry::{self, DirectoryEntry};
+use crate::operations::xattr;
 use crate::types::ipfs::IpfsHash;
 
 lazy_static! {
@@ -17,7 +17,12 @@ lazy_static! {
 
     static ref INSERT_QUERY: String = format!(
     "INSERT INTO catalog ({}) \
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)", directoryentry::DATABASE_FIELDS
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", directoryentry::DATABASE_FIELDS
+    );
+
+    static ref REPLACE_QUERY: String = format!(
+    "INSERT OR REPLACE INTO catalog ({}) \
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", directoryentry::DATABASE_FIELDS
     );
 
     static ref FIND_PATH: String = format!(
@@ -32,6 +37,49 @@ lazy_static! {
     "SELECT path, hash, size \
         FROM nested_catalogs;"
     );
+    static ref DELETE_PATH: String = String::from(
+    "DELETE FROM catalog \
+        WHERE path = ?;"
+    );
+
+    static ref REPLACE_NESTED: String = String::from(
+    "INSERT OR REPLACE INTO nested_catalogs (path, hash, size) \
+        VALUES (?, ?, ?);"
+    );
+
+    static ref DELETE_NESTED: String = String::from(
+    "DELETE FROM nested_catalogs \
+        WHERE path = ?;"
+    );
+
+    static ref LIST_HARDLINKS: String = format!(
+    "SELECT {} \
+        FROM catalog \
+        WHERE hardlinks >> 32 > 0;", directoryentry::DATABASE_FIELDS
+    );
+
+    static ref COUNT_ENTRIES: String = String::from(
+    "SELECT COUNT(*) \
+        FROM catalog;"
+    );
+
+    static ref LIST_CHUNKS: String = format!(
+    "SELECT {} \
+        FROM chunks \
+        WHERE path = ? \
+        ORDER BY offset ASC;", chunk::DATABASE_FIELDS
+    );
+
+    static ref INSERT_CHUNK: String = format!(
+    "INSERT INTO chunks (path, {}) \
+        VALUES (?, ?, ?, ?);", chunk::DATABASE_FIELDS
+    );
+
+    static ref DELETE_CHUNKS: String = String::from(
+    "DELETE FROM chunks \
+        WHERE path = ?;"
+    );
+
     static ref CREATE_CATALOG: String = String::from(
     "CREATE TABLE catalog
         (path TEXT, parent TEXT,\
@@ -50,14 +98,56 @@ lazy_static! {
     "CREATE TABLE nested_catalogs (path TEXT, hash TEXT, size INTEGER, \
         CONSTRAINT pk_nested_catalogs PRIMARY KEY (path));"
     );
+
+    static ref CREATE_CHUNKS: String = String::from(
+    "CREATE TABLE IF NOT EXISTS chunks (path TEXT, offset INTEGER, size INTEGER, hash TEXT, \
+        CONSTRAINT pk_chunks PRIMARY KEY (path, offset));"
+    );
+
+    static ref CREATE_PROPERTIES: String = String::from(
+    "CREATE TABLE properties (key TEXT, value TEXT, \
+        CONSTRAINT pk_properties PRIMARY KEY (key));"
+    );
+
+    static ref HAS_PROPERTIES: String = String::from(
+    "SELECT COUNT(*) \
+        FROM sqlite_master \
+        WHERE type = 'table' AND name = 'properties';"
+    );
+
+    static ref FIND_PROPERTY: String = String::from(
+    "SELECT value \
+        FROM properties \
+        WHERE key = ?;"
+    );
+
+    static ref REPLACE_PROPERTY: String = String::from(
+    "INSERT OR REPLACE INTO properties (key, value) \
+        VALUES (?, ?);"
+    );
+
+    /// Statements upgrading a catalog to each schema revision, applied in
+    /// order starting from the revision following the one of the catalog.
+    static ref MIGRATIONS: Vec<(i64, String)> = vec![
+        (2, format!("{}; {};", CREATE_CHUNKS.as_str(), CREATE_PROPERTIES.as_str())),
+        // entries published before owners were recorded keep no owner, so
+        // mounts report them as owned by the mounting user
+        (3, String::new()),
+        // a single link and no hardlink group
+        (4, String::from("UPDATE catalog SET hardlinks = 1 WHERE hardlinks IS NULL;")),
+    ];
 }
 
+/// Revision of the catalog schema written by this version. Catalogs without
+/// a `properties` table predate versioning and are at revision 1.
+pub static SCHEMA_REVISION: i64 = 4;
 
-pub fn add_directory_entry(connection: &Connection, dirent: &DirectoryEntry) -> Result<(), QFSError> {
-    let mut statement = connection
-        .prepare(INSERT_QUERY.as_str())
-        .unwrap();
-    let result = statement.insert(&[
+static SCHEMA_REVISION_KEY: &str = "schema_revision";
+
+
+fn write_directory_entry(connection: &Connection, query: &str, dirent: &DirectoryEntry) -> Result<(), QFSError> {
+    let mut statement = connection.prepare(query)?;
+    let result = statement.execute(&[
         &dirent.path.to_string() as &dyn ToSql,
         &dirent.parent.to_string() as &dyn ToSql,
         &dirent.hash.to_string() as &dyn ToSql,
@@ -67,13 +157,72 @@ pub fn add_directory_entry(connection: &Connection, dirent: &DirectoryEntry) ->
         &dirent.mtime,
         &dirent.name,
         &dirent.symlink,
+        &dirent.uid,
+        &dirent.gid,
+        &xattr::encode(&dirent.xattrs),
+        &dirent.hardlinks(),
     ]);
-    match result {
-        Ok(num_rows) => if num_rows == 1 { Ok(()) } else { Err(QFSError::new("Error adding a directory entry")) },
-        Err(error) => Err(QFSError::new(error.description()))
+    match result? {
+        1 => Ok(()),
+        _ => Err(QFSError::new("Error adding a directory entry")),
     }
 }
 
+pub fn add_directory_entry(connection: &Connection, dirent: &DirectoryEntry) -> Result<(), QFSError> {
+    write_directory_entry(connection, INSERT_QUERY.as_str(), dirent)
+}
+
+pub fn update_directory_entry(connection: &Connection, dirent: &DirectoryEntry) -> Result<(), QFSError> {
+    write_directory_entry(connection, REPLACE_QUERY.as_str(), dirent)
+}
+
+pub fn remove_directory_entry(connection: &Connection, hashed_path: String) -> Result<(), QFSError> {
+    connection.execute(DELETE_PATH.as_str(), &[&hashed_path])
+        .map_err(QFSError::from)?;
+    remove_chunks(connection, hashed_path)
+}
+
+pub fn list_chunks(connection: &Connection, hashed_path: String) -> Result<Vec<Chunk>, QFSError> {
+    let mut statement = connection.prepare(LIST_CHUNKS.as_str())?;
+    let mut rows = statement.query(&[hashed_path])?;
+    let mut chunks = Vec::new();
+    while let Some(row) = rows.next()? {
+        chunks.push(Chunk::from_sql_row(row)?);
+    }
+    Ok(chunks)
+}
+
+pub fn add_chunks(connection: &Connection, hashed_path: String, chunks: &[Chunk]) -> Result<(), QFSError> {
+    let mut statement = connection.prepare(INSERT_CHUNK.as_str())?;
+    for chunk in chunks.iter() {
+        statement.execute(&[
+            &hashed_path as &dyn ToSql,
+            &chunk.offset,
+            &chunk.size,
+            &chunk.hash.to_string(),
+        ])?;
+    }
+    Ok(())
+}
+
+pub fn remove_chunks(connection: &Connection, hashed_path: String) -> Result<(), QFSError> {
+    connection.execute(DELETE_CHUNKS.as_str(), &[hashed_path])
+        .map_err(QFSError::from)?;
+    Ok(())
+}
+
+pub fn update_nested(connection: &Connection, reference: &CatalogReference) -> Result<(), QFSError> {
+    connection.execute(
+        REPLACE_NESTED.as_str(),
+        &[
+            &reference.path().to_string() as &dyn ToSql,
+            &reference.hash().to_string() as &dyn ToSql,
+            &reference.size(),
+        ],
+    ).map_err(QFSError::from)?;
+    Ok(())
+}
+
 pub fn create_catalog(connection: &Connection) -> Result<(), QFSError> {
     connection.execute_batch(
         format!(
@@ -81,52 +230,123 @@ pub fn create_catalog(connection: &Connection) -> Result<(), QFSError> {
                 {}; \
                 {}; \
                 {}; \
+                {}; \
+                {}; \
             COMMIT;",
-            CREATE_CATALOG.as_str(), CREATE_INDEX.as_str(), CREATE_NESTED_CATALOGS.as_str()
+            CREATE_CATALOG.as_str(), CREATE_INDEX.as_str(), CREATE_NESTED_CATALOGS.as_str(),
+            CREATE_CHUNKS.as_str(), CREATE_PROPERTIES.as_str()
         ).as_str()
-    ).map_err(QFSError::from)
+    )?;
+    set_schema_revision(connection, SCHEMA_REVISION)
+}
+
+pub fn schema_revision(connection: &Connection) -> Result<i64, QFSError> {
+    let tables: i64 = connection.query_row(HAS_PROPERTIES.as_str(), NO_PARAMS, |row| row.get(0))?;
+    if tables == 0 {
+        return Ok(1);
+    }
+    let mut statement = connection.prepare(FIND_PROPERTY.as_str())?;
+    let mut rows = statement.query(&[SCHEMA_REVISION_KEY])?;
+    let value: String = match rows.next()? {
+        Some(row) => row.get(0)?,
+        None => return Err(QFSError::new("Catalog without a schema revision")),
+    };
+    value.parse::<i64>()
+        .map_err(|_| QFSError::new(format!("Invalid schema revision {}", value).as_str()))
+}
+
+fn set_schema_revision(connection: &Connection, revision: i64) -> Result<(), QFSError> {
+    connection.execute(
+        REPLACE_PROPERTY.as_str(),
+        &[SCHEMA_REVISION_KEY, revision.to_string().as_str()],
+    )?;
+    Ok(())
+}
+
+/// Brings a catalog up to `SCHEMA_REVISION`, returning whether anything
+/// changed. Catalogs written by a newer version are refused, since they may
+/// hold data this version would silently drop.
+pub fn migrate(connection: &Connection) -> Result<bool, QFSError> {
+    let revision = schema_revision(connection)?;
+    if revision > SCHEMA_REVISION {
+        return Err(QFSError::UnsupportedSchema(revision));
+    }
+    if revision == SCHEMA_REVISION {
+        return Ok(false);
+    }
+    connection.execute_batch("BEGIN;")?;
+    let result = MIGRATIONS.iter()
+        .filter(|(target, _)| *target > revision)
+        .try_for_each(|(target, statements)| {
+            connection.execute_batch(statements.as_str())?;
+            set_schema_revision(connection, *target)
+        });
+    match result {
+        Ok(()) => connection.execute_batch("COMMIT;")?,
+        Err(error) => {
+            connection.execute_batch("ROLLBACK;")?;
+            return Err(error);
+        }
+    }
+    Ok(true)
 }
 
 pub fn list_nested(connection: &Connection) -> Result<Vec<CatalogReference>, QFSError> {
-    let mut statement = connection
-        .prepare(LIST_NESTED.as_str())
-        .unwrap();
+    let mut statement = connection.prepare(LIST_NESTED.as_str())?;
     let mut rows = statement.query(NO_PARAMS)?;
     let mut nested = Vec::new();
-    while let Ok(Some(row)) = rows.next() {
-        let path: String = row.get(0).unwrap();
-        let hash: String = row.get(1).unwrap();
+    while let Some(row) = rows.next()? {
+        let path: String = row.get(0)?;
+        let hash: String = row.get(1)?;
         let catalog_reference = CatalogReference::new(
-            &IpfsHash::new(path.as_str()).unwrap(),
-            &IpfsHash::new(hash.as_str()).unwrap(),
-            row.get(3).unwrap(),
+            path.as_str(),
+            &IpfsHash::new(hash.as_str())?,
+            row.get(2)?,
         );
         nested.push(catalog_reference);
     }
     Ok(nested)
 }
 
-pub fn find_directory_entry(connection: &Connection, hashed_path: String) -> Result<DirectoryEntry, QFSError> {
-    let mut statement = connection
-        .prepare(FIND_PATH.as_str())
-        .unwrap();
+pub fn remove_nested(connection: &Connection, path: &str) -> Result<(), QFSError> {
+    connection.execute(DELETE_NESTED.as_str(), &[path])
+        .map_err(QFSError::from)?;
+    Ok(())
+}
+
+pub fn count_entries(connection: &Connection) -> Result<i64, QFSError> {
+    connection.query_row(COUNT_ENTRIES.as_str(), NO_PARAMS, |row| row.get(0))
+        .map_err(QFSError::from)
+}
+
+/// Entries belonging to a hardlink group.
+pub fn list_hardlinks(connection: &Connection) -> Result<Vec<DirectoryEntry>, QFSError> {
+    let mut statement = connection.prepare(LIST_HARDLINKS.as_str())?;
+    let mut rows = statement.query(NO_PARAMS)?;
+    let mut dirents = Vec::new();
+    while let Some(row) = rows.next()? {
+        dirents.push(DirectoryEntry::from_sql_row(row)?);
+    }
+    Ok(dirents)
+}
+
+pub fn find_directory_entry(connection: &Connection, hashed_path: String) -> Result<Option<DirectoryEntry>, QFSError> {
+    let mut statement = connection.prepare(FIND_PATH.as_str())?;
 
     let mut rows = statement.query(&[hashed_path])?;
-    if let Ok(Some(row)) = rows.next() {
-        let dirent = DirectoryEntry::from_sql_row(row);
-        return Ok(dirent);
+    if let Some(row) = rows.next()? {
+        let dirent = DirectoryEntry::from_sql_row(row)?;
+        return Ok(Some(dirent));
     }
-    Err(QFSError::new("Entry not found"))
+    Ok(None)
 }
 
 pub fn list_directory(connection: &Connection, hashed_path: String) -> Result<Vec<DirectoryEntry>, QFSError> {
-    let mut statement = connection
-        .prepare(LISTING_QUERY.as_str())
-        .unwrap();
+    let mut statement = connection.prepare(LISTING_QUERY.as_str())?;
     let mut rows = statement.query(&[hashed_path])?;
     let mut dirents = Vec::new();
-    while let Ok(Some(row)) = rows.next() {
-        dirents.push(DirectoryEntry::from_sql_row(row));
+    while let Some(row) = rows.next()? {
+        dirents.push(DirectoryEntry::from_sql_row(row)?);
     }
     Ok(dirents)
 }
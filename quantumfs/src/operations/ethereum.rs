@@ -1,17 +1,31 @@
 use std::convert::TryFrom;
 
+use ethabi::{self, RawLog, Token};
 use serde_json;
-use serde_json::Value;
+use tiny_keccak::keccak256;
 use web3::contract::{Contract, Options};
+use web3::contract::tokens::Tokenize;
 use web3::futures::Future;
 use web3::transports::Http;
-use web3::types::{Address, U256};
+use web3::types::{Address, BlockNumber, FilterBuilder, H256, Log, TransactionReceipt, U256, U64};
 use web3::Web3;
 
 use crate::errors::QFSError;
 use crate::types::ipfs::IpfsHash;
 
-static CONTRACT_JSON_BYTES: &[u8] = include_bytes!("../../../ethereum/build/contracts/QuantumFS.json");
+static CONTRACT_ABI_BYTES: &[u8] = include_bytes!("../../abi/QuantumFS.json");
+
+lazy_static! {
+    static ref CONTRACT_ABI: ethabi::Contract = ethabi::Contract::load(CONTRACT_ABI_BYTES)
+        .expect("Invalid ABI");
+}
+
+/// Changes to the history of a file system, as logged by the contract.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RevisionEvent {
+    Added(IpfsHash, u128),
+    Evicted,
+}
 
 
 pub fn get_web3(url: &str) -> Web3<Http> {
@@ -27,14 +41,10 @@ pub fn parse_address(address: &str) -> Result<Address, QFSError> {
 }
 
 pub fn get_contract(web3: &Web3<Http>, address: Address) -> Contract<Http> {
-    let json: Value = serde_json::from_slice(CONTRACT_JSON_BYTES).expect("Malformed JSON ABI");
-    let abi_json = &json["abi"];
-    let abi_string = abi_json.to_string();
-    let abi_bytes = abi_string.as_bytes();
     Contract::from_json(
         web3.eth(),
         address,
-        &abi_bytes
+        CONTRACT_ABI_BYTES
     ).expect("Invalid ABI")
 }
 
@@ -45,12 +55,12 @@ fn map_result(result: (String, U256)) -> (IpfsHash, u128) {
     (hash, number)
 }
 
-pub fn fetch_revision(contract: &Contract<Http>, address: Address, revision: u128) -> Result<(IpfsHash, u128), QFSError> {
+pub fn fetch_revision(contract: &Contract<Http>, owner: Address, name: &str, revision: u128) -> Result<(IpfsHash, u128), QFSError> {
     let revision_uint = U256::try_from(revision).unwrap();
     contract
         .query("getRevision",
-               (revision_uint, ),
-               address,
+               (owner, name.to_string(), revision_uint),
+               None,
                Options::default(),
                None)
         .wait()
@@ -58,11 +68,11 @@ pub fn fetch_revision(contract: &Contract<Http>, address: Address, revision: u12
         .map(map_result)
 }
 
-pub fn fetch_last_revision(contract: &Contract<Http>, address: Address) -> Result<(IpfsHash, u128), QFSError> {
+pub fn fetch_last_revision(contract: &Contract<Http>, owner: Address, name: &str) -> Result<(IpfsHash, u128), QFSError> {
     contract
         .query("currentRevision",
-               (),
-               address,
+               (owner, name.to_string()),
+               None,
                Options::default(),
                None)
         .wait()
@@ -70,18 +80,199 @@ pub fn fetch_last_revision(contract: &Contract<Http>, address: Address) -> Resul
         .map(map_result)
 }
 
+pub fn fetch_total_revisions(contract: &Contract<Http>, owner: Address, name: &str) -> Result<u128, QFSError> {
+    contract
+        .query("totalRevisions",
+               (owner, name.to_string()),
+               None,
+               Options::default(),
+               None)
+        .wait()
+        .map_err(QFSError::from)
+        .map(|total: U256| total.as_u128())
+}
+
+fn send_transaction<P: Tokenize + Clone>(contract: &Contract<Http>, address: Address, function: &str, params: P) -> Result<TransactionReceipt, QFSError> {
+    let gas = contract
+        .estimate_gas(function, params.clone(), address, Options::default())
+        .wait()?;
+    let receipt = contract
+        .call_with_confirmations(function,
+                                 params,
+                                 address,
+                                 Options::with(|options| options.gas = Some(gas)),
+                                 0)
+        .wait()?;
+    if receipt.status != Some(U64::from(1)) {
+        return Err(QFSError::ledger(format!("The {} transaction failed", function).as_str()));
+    }
+    Ok(receipt)
+}
+
+pub fn evict(contract: &Contract<Http>, address: Address, name: &str) -> Result<(), QFSError> {
+    send_transaction(contract, address, "evict", (name.to_string(), ))?;
+    Ok(())
+}
+
+/// Lets `publisher` add revisions to the file system `name` of `address`.
+pub fn grant_publisher(contract: &Contract<Http>, address: Address, name: &str, publisher: Address) -> Result<(), QFSError> {
+    send_transaction(contract, address, "grantPublisher", (name.to_string(), publisher))?;
+    Ok(())
+}
+
+pub fn revoke_publisher(contract: &Contract<Http>, address: Address, name: &str, publisher: Address) -> Result<(), QFSError> {
+    send_transaction(contract, address, "revokePublisher", (name.to_string(), publisher))?;
+    Ok(())
+}
+
+/// Accounts granted to publish to the file system `name` of `owner`, besides the owner itself.
+pub fn fetch_publishers(contract: &Contract<Http>, owner: Address, name: &str) -> Result<Vec<Address>, QFSError> {
+    contract
+        .query("getPublishers",
+               (owner, name.to_string()),
+               None,
+               Options::default(),
+               None)
+        .wait()
+        .map_err(QFSError::from)
+}
+
+pub fn is_publisher(contract: &Contract<Http>, owner: Address, name: &str, account: Address) -> Result<bool, QFSError> {
+    contract
+        .query("isPublisher",
+               (owner, name.to_string(), account),
+               None,
+               Options::default(),
+               None)
+        .wait()
+        .map_err(QFSError::from)
+}
+
+fn event_signature(name: &str) -> H256 {
+    CONTRACT_ABI.event(name).expect("Invalid ABI").signature()
+}
+
+fn owner_topic(owner: Address) -> H256 {
+    let mut topic = H256::zero();
+    topic[12..].copy_from_slice(&owner[..]);
+    topic
+}
+
+/// Indexed strings are logged as the hash of their content.
+fn name_topic(name: &str) -> H256 {
+    H256::from(keccak256(name.as_bytes()))
+}
+
+/// Decodes a log of the contract, returning `None` for logs of other
+/// events and for logs dropped by a chain reorganization.
+pub fn decode_revision_event(log: &Log) -> Result<Option<RevisionEvent>, QFSError> {
+    if log.is_removed() {
+        return Ok(None);
+    }
+    let name = match log.topics.first() {
+        Some(topic) if *topic == event_signature("RevisionAdded") => "RevisionAdded",
+        Some(topic) if *topic == event_signature("Evicted") => "Evicted",
+        _ => return Ok(None),
+    };
+    let raw = RawLog { topics: log.topics.clone(), data: log.data.0.clone() };
+    let params = CONTRACT_ABI.event(name)?.parse_log(raw)?.params;
+    if name == "Evicted" {
+        return Ok(Some(RevisionEvent::Evicted));
+    }
+    let (mut hash, mut revision) = (None, None);
+    for param in params {
+        match (param.name.as_str(), param.value) {
+            ("hash", Token::String(value)) => hash = Some(IpfsHash::new(value.as_str())?),
+            ("revision", Token::Uint(value)) => revision = Some(value.as_u128()),
+            _ => {}
+        }
+    }
+    match (hash, revision) {
+        (Some(hash), Some(revision)) => Ok(Some(RevisionEvent::Added(hash, revision))),
+        _ => Err(QFSError::ledger("Malformed RevisionAdded event")),
+    }
+}
+
+/// Replays the events of a file system in the order they were logged,
+/// returning the hash of each of its current revisions.
+pub fn replay_history(events: &[RevisionEvent]) -> Result<Vec<(IpfsHash, u128)>, QFSError> {
+    let mut history: Vec<(IpfsHash, u128)> = Vec::new();
+    for event in events {
+        match event {
+            RevisionEvent::Added(hash, revision) => {
+                if *revision != history.len() as u128 + 1 {
+                    return Err(QFSError::ledger("The revision events are not contiguous"));
+                }
+                history.push((hash.clone(), *revision));
+            }
+            RevisionEvent::Evicted => history.clear(),
+        }
+    }
+    Ok(history)
+}
+
+/// Reconstructs the history of the file system `name` of `owner` from the
+/// logs of the contract, without querying each revision.
+pub fn fetch_revision_history(web3: &Web3<Http>, contract: &Contract<Http>, owner: Address, name: &str) -> Result<Vec<(IpfsHash, u128)>, QFSError> {
+    let filter = FilterBuilder::default()
+        .address(vec![contract.address()])
+        .topics(Some(vec![event_signature("RevisionAdded"), event_signature("Evicted")]),
+                Some(vec![owner_topic(owner)]),
+                Some(vec![name_topic(name)]),
+                None)
+        .from_block(BlockNumber::Earliest)
+        .to_block(BlockNumber::Latest)
+        .build();
+    let logs = web3.eth().logs(filter).wait()?;
+    let mut events = Vec::new();
+    for log in logs.iter() {
+        if let Some(event) = decode_revision_event(log)? {
+            events.push(event);
+        }
+    }
+    replay_history(&events)
+}
+
+/// Finds the revision added by a transaction in the logs of its receipt.
+fn added_revision(contract: &Contract<Http>, receipt: &TransactionReceipt) -> Result<(IpfsHash, u128), QFSError> {
+    for log in receipt.logs.iter().filter(|log| log.address == contract.address()) {
+        if let Some(RevisionEvent::Added(hash, revision)) = decode_revision_event(log)? {
+            return Ok((hash, revision));
+        }
+    }
+    Err(QFSError::ledger("The addRevision transaction logged no revision"))
+}
+
+/// Adds a revision to the file system `name` of `owner`, signed by `address`,
+/// which must be the owner or one of its publishers. The contract rejects it
+/// unless the file system is still at the `base` revision it was made from.
+pub fn push_revision(contract: &Contract<Http>, address: Address, owner: Address, name: &str, base: u128,
+                     hash: &IpfsHash) -> Result<(IpfsHash, u128), QFSError> {
+    let params = (owner, name.to_string(), U256::from(base), hash.to_string());
+    let receipt = send_transaction(contract, address, "addRevision", params)?;
+    let (pushed_hash, revision) = added_revision(contract, &receipt)?;
+    if &pushed_hash != hash {
+        return Err(QFSError::ledger("The pushed hash is not the logged revision"));
+    }
+    Ok((pushed_hash, revision))
+}
+
 
 #[cfg(test)]
 pub mod tests {
+    use std::fs;
+
+    use ethabi::{self, Token};
     use serde_json::{self, Value};
     use web3::contract::Contract;
     use web3::futures::Future;
     use web3::transports::Http;
-    use web3::types::Address;
+    use web3::types::{Address, Bytes, H2048, H256, Log, TransactionReceipt, U128, U256};
     use web3::Web3;
 
     use crate::operations::ethereum;
-    use crate::operations::ethereum::{CONTRACT_JSON_BYTES, get_contract, get_web3};
+    use crate::operations::ethereum::{get_contract, get_web3, RevisionEvent};
+    use crate::types::ipfs::IpfsHash;
 
     pub fn coinbase(web3: &Web3<Http>) -> Address {
         web3.eth().coinbase().wait()
@@ -93,19 +284,145 @@ pub mod tests {
         pub static ref TEST_CONTRACT: Contract<Http> = get_contract(&TEST_WEB3, get_contract_address());
     }
 
+    pub static NAME: &str = "default";
+
+    static CONTRACT_BUILD_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../ethereum/build/contracts/QuantumFS.json");
+
     fn get_contract_address() -> Address {
-        let json: Value = serde_json::from_slice(CONTRACT_JSON_BYTES).expect("Malformed JSON ABI");
+        let bytes = fs::read(CONTRACT_BUILD_PATH)
+            .expect("Could not read the contract build. Deploy it with truffle first");
+        let json: Value = serde_json::from_slice(&bytes).expect("Malformed JSON ABI");
         let contract_address = &json["networks"]["5777"]["address"];
         let address_string = contract_address.as_str().unwrap().to_lowercase();
         ethereum::parse_address(address_string.as_str())
             .expect("Failure parsing the contract address")
     }
 
+    pub fn accounts(web3: &Web3<Http>) -> Vec<Address> {
+        web3.eth().accounts().wait()
+            .expect("Could not get the accounts. Check the connection with the ethereum node")
+    }
+
+    #[test]
+    fn push_revision_should_update_the_current_revision() {
+        let address = accounts(&TEST_WEB3)[1];
+        let (_, previous) = ethereum::fetch_last_revision(&TEST_CONTRACT, address, NAME).unwrap();
+        let hash = IpfsHash::new("QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4").unwrap();
+        let (pushed_hash, revision) = ethereum::push_revision(&TEST_CONTRACT, address, address, NAME, previous, &hash).unwrap();
+        assert_eq!(pushed_hash, hash);
+        assert_eq!(revision, previous + 1);
+        assert!(ethereum::push_revision(&TEST_CONTRACT, address, address, NAME, previous, &hash).is_err());
+    }
+
     #[test]
     fn fetch_last_revision_without_interaction_should_work() {
         let coinbase = coinbase(&TEST_WEB3);
-        let result = ethereum::fetch_last_revision(&TEST_CONTRACT,coinbase).unwrap();
+        let result = ethereum::fetch_last_revision(&TEST_CONTRACT, coinbase, NAME).unwrap();
         assert_eq!(result.0.to_string().as_str(), "0000000000000000000000000000000000000000000000");
         assert_eq!(result.1, 0);
     }
+
+    fn log(topics: Vec<H256>, data: Vec<Token>) -> Log {
+        Log {
+            address: Address::zero(),
+            topics,
+            data: Bytes(ethabi::encode(&data)),
+            block_hash: None,
+            block_number: None,
+            transaction_hash: None,
+            transaction_index: None,
+            log_index: None,
+            transaction_log_index: None,
+            log_type: None,
+            removed: None,
+        }
+    }
+
+    fn added_log(owner: Address, hash: &str, revision: u64) -> Log {
+        let mut revision_topic = H256::zero();
+        U256::from(revision).to_big_endian(&mut revision_topic[..]);
+        log(vec![ethereum::event_signature("RevisionAdded"), ethereum::owner_topic(owner),
+                 ethereum::name_topic(NAME), revision_topic],
+            vec![Token::String(hash.to_string())])
+    }
+
+    #[test]
+    fn replay_history_should_follow_the_logs() {
+        let owner = Address::from_low_u64_be(1);
+        let hash1 = "QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4";
+        let hash2 = "QmWE6s8qazNrzGEHLfVA5PAFieT1nsoqU11pggfoWwSis5";
+        let evicted = log(vec![ethereum::event_signature("Evicted"), ethereum::owner_topic(owner),
+                               ethereum::name_topic(NAME)],
+                          vec![Token::Uint(U256::from(2))]);
+        let mut removed = added_log(owner, hash1, 2);
+        removed.removed = Some(true);
+        let unrelated = log(vec![H256::zero()], Vec::new());
+        let logs = [added_log(owner, hash1, 1), added_log(owner, hash2, 2), evicted,
+                    removed, unrelated, added_log(owner, hash2, 1)];
+
+        let events: Vec<RevisionEvent> = logs.iter()
+            .filter_map(|log| ethereum::decode_revision_event(log).unwrap())
+            .collect();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0], RevisionEvent::Added(IpfsHash::new(hash1).unwrap(), 1));
+        assert_eq!(events[2], RevisionEvent::Evicted);
+        let history = ethereum::replay_history(&events).unwrap();
+        assert_eq!(history, vec![(IpfsHash::new(hash2).unwrap(), 1)]);
+        assert!(ethereum::replay_history(&events[1..]).is_err());
+    }
+
+    #[test]
+    fn added_revision_should_come_from_the_receipt() {
+        let owner = Address::from_low_u64_be(1);
+        let contract = get_contract(&TEST_WEB3, Address::from_low_u64_be(2));
+        let hash = "QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4";
+        let mut added = added_log(owner, hash, 7);
+        added.address = contract.address();
+        let mut receipt = TransactionReceipt {
+            transaction_hash: H256::zero(),
+            transaction_index: U128::from(0),
+            block_hash: None,
+            block_number: None,
+            cumulative_gas_used: U256::zero(),
+            gas_used: None,
+            contract_address: None,
+            logs: vec![added_log(owner, "QmWE6s8qazNrzGEHLfVA5PAFieT1nsoqU11pggfoWwSis5", 8), added],
+            status: None,
+            logs_bloom: H2048::zero(),
+        };
+        assert_eq!(ethereum::added_revision(&contract, &receipt).unwrap(), (IpfsHash::new(hash).unwrap(), 7));
+        receipt.logs.pop();
+        assert!(ethereum::added_revision(&contract, &receipt).is_err());
+    }
+
+    #[test]
+    fn fetch_revision_history_should_match_the_revisions() {
+        let address = accounts(&TEST_WEB3)[2];
+        let hash = IpfsHash::new("QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4").unwrap();
+        let total = ethereum::fetch_total_revisions(&TEST_CONTRACT, address, NAME).unwrap();
+        ethereum::push_revision(&TEST_CONTRACT, address, address, NAME, total, &hash).unwrap();
+        let history = ethereum::fetch_revision_history(&TEST_WEB3, &TEST_CONTRACT, address, NAME).unwrap();
+        assert_eq!(history.len() as u128, ethereum::fetch_total_revisions(&TEST_CONTRACT, address, NAME).unwrap());
+        for (hash, revision) in history {
+            assert_eq!(ethereum::fetch_revision(&TEST_CONTRACT, address, NAME, revision).unwrap().0, hash);
+        }
+    }
+
+    #[test]
+    fn granted_publishers_should_push_to_the_owner_file_system() {
+        let accounts = accounts(&TEST_WEB3);
+        let (owner, publisher) = (accounts[3], accounts[4]);
+        let name = "publishers";
+        let hash = IpfsHash::new("QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4").unwrap();
+        let total = ethereum::fetch_total_revisions(&TEST_CONTRACT, owner, name).unwrap();
+        assert!(ethereum::push_revision(&TEST_CONTRACT, publisher, owner, name, total, &hash).is_err());
+        ethereum::grant_publisher(&TEST_CONTRACT, owner, name, publisher).unwrap();
+        assert!(ethereum::is_publisher(&TEST_CONTRACT, owner, name, publisher).unwrap());
+        assert!(ethereum::fetch_publishers(&TEST_CONTRACT, owner, name).unwrap().contains(&publisher));
+        let (pushed_hash, _) = ethereum::push_revision(&TEST_CONTRACT, publisher, owner, name, total, &hash).unwrap();
+        assert_eq!(pushed_hash, hash);
+        ethereum::revoke_publisher(&TEST_CONTRACT, owner, name, publisher).unwrap();
+        assert!(!ethereum::is_publisher(&TEST_CONTRACT, owner, name, publisher).unwrap());
+        assert!(ethereum::push_revision(&TEST_CONTRACT, publisher, owner, name, total + 1, &hash).is_err());
+    }
 }
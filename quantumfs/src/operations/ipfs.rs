@@ -1,13 +1,16 @@
 use std::fmt::{Debug, Error, Formatter};
 use std::fs::File;
+use std::io::Cursor;
 
 use base58;
 use base58::ToBase58;
 use ipfsapi::IpfsApi;
 use multihash;
 use regex::Regex;
+use reqwest::{Client, StatusCode};
 
 use crate::errors::QFSError;
+use crate::operations::store::ObjectStore;
 use crate::types::ipfs::IpfsHash;
 
 static IPFS_HASH_PATTERN: &str = "^[a-zA-z0-9]{46}$";
@@ -24,8 +27,18 @@ pub fn hash_bytes(bytes: &[u8]) -> String {
         .to_base58()
 }
 
+/// Checks that `bytes` are the contents of the object addressed by `hash`.
+pub fn verify_bytes(hash: &IpfsHash, bytes: &[u8]) -> Result<(), QFSError> {
+    let actual = hash_bytes(bytes);
+    if hash.as_ref() != actual.as_str() {
+        return Err(QFSError::Integrity { expected: hash.clone(), actual });
+    }
+    Ok(())
+}
+
 pub struct IPFS {
-    api: IpfsApi
+    api: IpfsApi,
+    url: String,
 }
 
 impl Debug for IPFS {
@@ -37,10 +50,19 @@ impl Debug for IPFS {
 impl IPFS {
     pub fn new(server: &str, port: u16) -> IPFS {
         Self {
-            api: IpfsApi::new(server, port)
+            api: IpfsApi::new(server, port),
+            url: format!("http://{}:{}/api/v0", server, port),
         }
     }
 
+    fn block_command(&self, command: &str, ipfs_hash: &IpfsHash) -> Result<StatusCode, QFSError> {
+        let response = Client::new()
+            .post(format!("{}/block/{}", self.url, command).as_str())
+            .query(&[("arg", ipfs_hash.as_ref())])
+            .send()?;
+        Ok(response.status())
+    }
+
     pub fn stream(&self, ipfs_hash: &IpfsHash) -> Result<impl Iterator<Item=u8>, QFSError> {
         self.api
             .block_get(ipfs_hash.to_string().as_str())
@@ -60,10 +82,40 @@ impl IPFS {
     }
 }
 
+impl ObjectStore for IPFS {
+    fn put(&self, data: &[u8]) -> Result<IpfsHash, QFSError> {
+        let hash = self.api
+            .block_put(Cursor::new(data.to_vec()))
+            .map_err(QFSError::from)?;
+        IpfsHash::new(hash.as_str())
+    }
+
+    fn stream(&self, hash: &IpfsHash) -> Result<Box<dyn Iterator<Item=u8>>, QFSError> {
+        Ok(Box::new(IPFS::stream(self, hash)?))
+    }
+
+    fn exists(&self, hash: &IpfsHash) -> Result<bool, QFSError> {
+        Ok(self.block_command("stat", hash)?.is_success())
+    }
+
+    fn delete(&self, hash: &IpfsHash) -> Result<(), QFSError> {
+        let status = self.block_command("rm", hash)?;
+        if !status.is_success() {
+            return Err(QFSError::storage(format!("Could not remove {}: {}", hash, status).as_str()));
+        }
+        Ok(())
+    }
+
+    fn put_file(&self, file: &File) -> Result<IpfsHash, QFSError> {
+        self.add(file)
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
-    use crate::operations::ipfs::{hash_bytes, IPFS, IpfsHash, validate_ipfs_hash};
+    use crate::errors::QFSError;
+    use crate::operations::ipfs::{hash_bytes, IPFS, IpfsHash, validate_ipfs_hash, verify_bytes};
 
     fn ipfs() -> IPFS {
         IPFS::new("127.0.0.1", 5001)
@@ -90,4 +142,14 @@ mod tests {
         let result = hash_bytes(b"hello world");
         assert_eq!(result, "QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4");
     }
+
+    #[test]
+    fn verify_bytes_should_detect_tampered_objects() {
+        let hash = IpfsHash::new("QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4").unwrap();
+        assert!(verify_bytes(&hash, b"hello world").is_ok());
+        match verify_bytes(&hash, b"hello censored world") {
+            Err(QFSError::Integrity { actual, .. }) => assert_eq!(actual, hash_bytes(b"hello censored world")),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
 }
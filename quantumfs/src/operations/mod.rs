@@ -2,3 +2,6 @@ pub mod ipfs;
 pub mod ethereum;
 pub mod path;
 pub mod database;
+pub mod overlay;
+pub mod store;
+pub mod xattr;
@@ -0,0 +1,46 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+
+pub static WHITEOUT_PREFIX: &str = ".wh.";
+pub static OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+pub fn whiteout_name(name: &OsStr) -> String {
+    format!("{}{}", WHITEOUT_PREFIX, name.to_string_lossy())
+}
+
+pub fn whiteout_target(file_name: &str) -> Option<&str> {
+    if file_name == OPAQUE_MARKER || !file_name.starts_with(WHITEOUT_PREFIX) {
+        return None;
+    }
+    Some(&file_name[WHITEOUT_PREFIX.len()..])
+}
+
+pub fn is_special(file_name: &str) -> bool {
+    file_name.starts_with(WHITEOUT_PREFIX)
+}
+
+pub fn is_opaque(directory: &Path) -> bool {
+    fs::symlink_metadata(directory.join(OPAQUE_MARKER)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use crate::operations::overlay::{is_special, OPAQUE_MARKER, whiteout_name, whiteout_target};
+
+    #[test]
+    fn test_whiteout_target() {
+        assert_eq!(whiteout_target(whiteout_name(OsStr::new("file1")).as_str()), Some("file1"));
+        assert_eq!(whiteout_target("file1"), None);
+        assert_eq!(whiteout_target(OPAQUE_MARKER), None);
+    }
+
+    #[test]
+    fn test_is_special() {
+        assert!(is_special(OPAQUE_MARKER));
+        assert!(is_special(".wh.file1"));
+        assert!(!is_special(".file1"));
+    }
+}
@@ -12,12 +12,26 @@ pub fn canonicalize_path(path: &str) -> String {
 pub fn is_sanitized(needle_path: &str, catalog_path: &str) -> bool {
     needle_path.len() == catalog_path.len() ||
         (needle_path.len() > catalog_path.len() &&
-        needle_path.chars().last().unwrap() == '/')
+        needle_path.as_bytes()[catalog_path.len()] == b'/')
+}
+
+pub fn join_path(parent: &str, name: &str) -> String {
+    match parent {
+        "/" => format!("/{}", name),
+        _ => format!("{}/{}", parent, name),
+    }
+}
+
+pub fn parent_path(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(index) => &path[..index],
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::operations::path::{canonicalize_path, is_sanitized};
+    use crate::operations::path::{canonicalize_path, is_sanitized, join_path, parent_path};
 
     #[test]
     fn test_canonicalize_path() {
@@ -29,5 +43,19 @@ mod tests {
     #[test]
     fn test_is_sanitized() {
         assert!(is_sanitized("/1/2/3/", "/1/2/3"));
+        assert!(is_sanitized("/1/2/3/4", "/1/2/3"));
+        assert!(!is_sanitized("/1/2/34", "/1/2/3"));
+    }
+
+    #[test]
+    fn test_join_path() {
+        assert_eq!("/1", join_path("/", "1"));
+        assert_eq!("/1/2", join_path("/1", "2"));
+    }
+
+    #[test]
+    fn test_parent_path() {
+        assert_eq!("/", parent_path("/1"));
+        assert_eq!("/1/2", parent_path("/1/2/3"));
     }
 }
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use tempfile::NamedTempFile;
+
+use crate::errors::QFSError;
+use crate::operations::ipfs::hash_bytes;
+use crate::types::ipfs::IpfsHash;
+
+/// Content-addressed storage holding the catalogs and file contents of a
+/// repository. Objects are addressed by the IPFS hash of their bytes.
+pub trait ObjectStore: Debug + Send + Sync {
+    fn put(&self, data: &[u8]) -> Result<IpfsHash, QFSError>;
+
+    fn stream(&self, hash: &IpfsHash) -> Result<Box<dyn Iterator<Item=u8>>, QFSError>;
+
+    fn exists(&self, hash: &IpfsHash) -> Result<bool, QFSError>;
+
+    fn delete(&self, hash: &IpfsHash) -> Result<(), QFSError>;
+
+    fn get(&self, hash: &IpfsHash) -> Result<Vec<u8>, QFSError> {
+        let bytes = self.stream(hash)?.collect();
+        Ok(bytes)
+    }
+
+    fn put_file(&self, file: &File) -> Result<IpfsHash, QFSError> {
+        let mut data = Vec::new();
+        file.try_clone()?.read_to_end(&mut data)?;
+        self.put(&data)
+    }
+}
+
+fn object_not_found(hash: &IpfsHash) -> QFSError {
+    QFSError::storage(format!("Object {} not found", hash).as_str())
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    objects: RwLock<HashMap<IpfsHash, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ObjectStore for MemoryStore {
+    fn put(&self, data: &[u8]) -> Result<IpfsHash, QFSError> {
+        let hash = IpfsHash::new(hash_bytes(data).as_str())?;
+        self.objects.write().unwrap().insert(hash.clone(), data.to_vec());
+        Ok(hash)
+    }
+
+    fn stream(&self, hash: &IpfsHash) -> Result<Box<dyn Iterator<Item=u8>>, QFSError> {
+        let bytes = self.get(hash)?;
+        Ok(Box::new(bytes.into_iter()))
+    }
+
+    fn exists(&self, hash: &IpfsHash) -> Result<bool, QFSError> {
+        Ok(self.objects.read().unwrap().contains_key(hash))
+    }
+
+    fn delete(&self, hash: &IpfsHash) -> Result<(), QFSError> {
+        self.objects.write().unwrap().remove(hash);
+        Ok(())
+    }
+
+    fn get(&self, hash: &IpfsHash) -> Result<Vec<u8>, QFSError> {
+        self.objects.read().unwrap()
+            .get(hash)
+            .cloned()
+            .ok_or_else(|| object_not_found(hash))
+    }
+}
+
+/// Stores every object as a plain file named after its hash.
+#[derive(Debug)]
+pub struct DirectoryStore {
+    root: PathBuf,
+}
+
+impl DirectoryStore {
+    pub fn new(root: &Path) -> Result<Self, QFSError> {
+        fs::create_dir_all(root)?;
+        Ok(Self {
+            root: root.to_owned(),
+        })
+    }
+
+    fn object_path(&self, hash: &IpfsHash) -> PathBuf {
+        self.root.join(hash.as_ref())
+    }
+}
+
+impl ObjectStore for DirectoryStore {
+    fn put(&self, data: &[u8]) -> Result<IpfsHash, QFSError> {
+        let hash = IpfsHash::new(hash_bytes(data).as_str())?;
+        let mut tmpfile = NamedTempFile::new_in(self.root.as_path())?;
+        tmpfile.write_all(data)?;
+        tmpfile.persist(self.object_path(&hash))
+            .map_err(|error| QFSError::from(error.error))?;
+        Ok(hash)
+    }
+
+    /// Reads the whole object up front, so that failing reads are reported
+    /// instead of cutting the object short.
+    fn stream(&self, hash: &IpfsHash) -> Result<Box<dyn Iterator<Item=u8>>, QFSError> {
+        let bytes = self.get(hash)?;
+        Ok(Box::new(bytes.into_iter()))
+    }
+
+    fn exists(&self, hash: &IpfsHash) -> Result<bool, QFSError> {
+        Ok(self.object_path(hash).is_file())
+    }
+
+    fn delete(&self, hash: &IpfsHash) -> Result<(), QFSError> {
+        match fs::remove_file(self.object_path(hash)) {
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Ok(()),
+            result => result.map_err(QFSError::from),
+        }
+    }
+
+    fn get(&self, hash: &IpfsHash) -> Result<Vec<u8>, QFSError> {
+        match fs::read(self.object_path(hash)) {
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Err(object_not_found(hash)),
+            result => result.map_err(QFSError::from),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use crate::operations::store::{DirectoryStore, MemoryStore, ObjectStore};
+    use crate::types::ipfs::IpfsHash;
+
+    fn check_store(store: &dyn ObjectStore) {
+        let hash = store.put(b"hello world").unwrap();
+        assert_eq!(hash.as_ref(), "QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4");
+        assert!(store.exists(&hash).unwrap());
+        assert_eq!(store.get(&hash).unwrap(), b"hello world".to_vec());
+        assert_eq!(store.stream(&hash).unwrap().collect::<Vec<u8>>(), b"hello world".to_vec());
+        store.delete(&hash).unwrap();
+        assert!(!store.exists(&hash).unwrap());
+        assert!(store.get(&hash).is_err());
+    }
+
+    #[test]
+    fn memory_store_should_work() {
+        check_store(&MemoryStore::new());
+    }
+
+    #[test]
+    fn directory_store_should_work() {
+        let root = tempdir().unwrap();
+        check_store(&DirectoryStore::new(root.path()).unwrap());
+    }
+
+    #[test]
+    fn directory_store_should_report_unreadable_objects() {
+        let root = tempdir().unwrap();
+        let store = DirectoryStore::new(root.path()).unwrap();
+        let hash = IpfsHash::new("QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4").unwrap();
+        fs::create_dir(root.path().join(hash.as_ref())).unwrap();
+        assert!(store.get(&hash).is_err());
+        assert!(store.stream(&hash).is_err());
+    }
+}
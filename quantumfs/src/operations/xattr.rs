@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::io::{self, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+use crate::errors::QFSError;
+
+/// Extended attributes of an entry, by name.
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+/// Only attributes of this namespace are published, since the others are
+/// either managed by the kernel or meaningless on another machine.
+pub static PUBLISHED_NAMESPACE: &str = "user.";
+
+/// Namespace of the read-only attributes computed by the file system.
+pub static VIRTUAL_NAMESPACE: &str = "user.qfs.";
+
+fn is_published(name: &str) -> bool {
+    name.starts_with(PUBLISHED_NAMESPACE) && !name.starts_with(VIRTUAL_NAMESPACE)
+}
+
+/// Calls a listxattr-like function twice, first to learn the size of the
+/// result and then to fetch it, retrying if it grew in between.
+fn query<F: Fn(*mut libc::c_void, usize) -> isize>(call: F) -> io::Result<Vec<u8>> {
+    loop {
+        let size = call(ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buffer = vec![0u8; size as usize];
+        let read = call(buffer.as_mut_ptr() as *mut libc::c_void, buffer.len());
+        if read >= 0 {
+            buffer.truncate(read as usize);
+            return Ok(buffer);
+        }
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::ERANGE) {
+            return Err(error);
+        }
+    }
+}
+
+/// Reads the publishable extended attributes of a file, without following
+/// symlinks.
+pub fn read(path: &Path) -> io::Result<Xattrs> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|error| io::Error::new(ErrorKind::InvalidInput, error))?;
+    let mut xattrs = Xattrs::new();
+    let names = match query(|buffer, size| unsafe {
+        libc::llistxattr(path.as_ptr(), buffer as *mut libc::c_char, size)
+    }) {
+        Ok(names) => names,
+        Err(ref error) if error.raw_os_error() == Some(libc::ENOTSUP) => return Ok(xattrs),
+        Err(error) => return Err(error),
+    };
+    for name in names.split(|byte| *byte == 0).filter(|name| !name.is_empty()) {
+        let name = match std::str::from_utf8(name) {
+            Ok(name) if is_published(name) => name,
+            _ => continue,
+        };
+        let c_name = CString::new(name)?;
+        match query(|buffer, size| unsafe {
+            libc::lgetxattr(path.as_ptr(), c_name.as_ptr(), buffer, size)
+        }) {
+            Ok(value) => {
+                xattrs.insert(name.to_string(), value);
+            }
+            // removed since it was listed
+            Err(ref error) if error.raw_os_error() == Some(libc::ENODATA) => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Serializes attributes for the `xattr` column of the catalog, as a
+/// sequence of length-prefixed names and values. Entries without attributes
+/// are stored as NULL.
+pub fn encode(xattrs: &Xattrs) -> Option<Vec<u8>> {
+    if xattrs.is_empty() {
+        return None;
+    }
+    let mut data = Vec::new();
+    for (name, value) in xattrs.iter() {
+        for field in [name.as_bytes(), value.as_slice()].iter() {
+            data.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            data.extend_from_slice(field);
+        }
+    }
+    Some(data)
+}
+
+fn decode_field<'a>(data: &mut &'a [u8]) -> Result<&'a [u8], QFSError> {
+    let malformed = || QFSError::new("Malformed extended attributes");
+    if data.len() < 4 {
+        return Err(malformed());
+    }
+    let (length, rest) = data.split_at(4);
+    let length = u32::from_be_bytes(length.try_into().map_err(|_| malformed())?) as usize;
+    if rest.len() < length {
+        return Err(malformed());
+    }
+    let (field, rest) = rest.split_at(length);
+    *data = rest;
+    Ok(field)
+}
+
+pub fn decode(mut data: &[u8]) -> Result<Xattrs, QFSError> {
+    let mut xattrs = Xattrs::new();
+    while !data.is_empty() {
+        let name = String::from_utf8(decode_field(&mut data)?.to_vec())
+            .map_err(|_| QFSError::new("Malformed extended attribute name"))?;
+        let value = decode_field(&mut data)?.to_vec();
+        xattrs.insert(name, value);
+    }
+    Ok(xattrs)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+
+    use tempfile::tempdir;
+
+    use crate::operations::xattr::{self, Xattrs};
+
+    #[test]
+    fn decode_should_revert_encode() {
+        let mut xattrs = Xattrs::new();
+        xattrs.insert(String::from("user.mime_type"), b"text/plain".to_vec());
+        xattrs.insert(String::from("user.empty"), Vec::new());
+        let data = xattr::encode(&xattrs).unwrap();
+        assert_eq!(xattr::decode(&data).unwrap(), xattrs);
+        assert!(xattr::decode(&data[..data.len() - 1]).is_err());
+        assert!(xattr::encode(&Xattrs::new()).is_none());
+    }
+
+    #[test]
+    fn read_should_skip_unpublished_attributes() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file1");
+        fs::write(path.as_path(), "this is file1").unwrap();
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        for (name, value) in [("user.mime_type", "text/plain"), ("user.qfs.hash", "fake")].iter() {
+            let name = CString::new(*name).unwrap();
+            let result = unsafe {
+                libc::lsetxattr(c_path.as_ptr(), name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0)
+            };
+            assert_eq!(result, 0);
+        }
+        let xattrs = xattr::read(path.as_path()).unwrap();
+        assert_eq!(xattrs.len(), 1);
+        assert_eq!(xattrs["user.mime_type"], b"text/plain".to_vec());
+    }
+}
@@ -12,7 +12,7 @@ impl IpfsHash {
     pub fn new(hash: &str) -> Result<Self, QFSError> {
         match validate_ipfs_hash(hash) {
             true => Ok(Self { hash: String::from(hash) }),
-            false => Err(QFSError::new("Invalid IPFS hash")),
+            false => Err(QFSError::InvalidHash(String::from(hash))),
         }
     }
 }